@@ -28,7 +28,9 @@ pub fn read(buffer: &[u8]) -> ReadResult {
     let mut d = Avif::decode(
         buffer,
         &aom_decode::Config {
-            threads: num_cpus::get(),
+            // Decode with the threads of the current rayon pool, so that callers limit the number
+            // of threads by running in a smaller pool. Outside a pool this is the number of CPUs.
+            threads: rayon::current_num_threads(),
         },
     )
     .map_err(|err| format!("Failed to create decoder: {}", err))?;
@@ -88,6 +90,8 @@ fn compress_base(image: &Image, mut quality: u8, fast: bool) -> Result<Vec<u8>,
         .with_alpha_quality(if has_alpha { 100.0 } else { 1.0 })
         .with_internal_color_space(ravif::ColorSpace::YCbCr)
         .with_speed(if fast { 10 } else { 1 })
+        // Encode in the current rayon pool instead of spawning a pool of its own.
+        .with_num_threads(None)
         .encode_rgba(ravif::Img::new(&image.data, image.width, image.height))
         .map_err(|err| format!("Failed to compress image: {}", err))?;
 
@@ -0,0 +1,491 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Optimizing many inputs concurrently with the thread budget split between them.
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::common::Format;
+use crate::file::{
+    describe_choice, optimize_file, parse_format, report_file, Choice, Destination, Settings,
+    Written,
+};
+use crate::journal::{Entry, Journal};
+use crate::output::{is_temporary_file, Output};
+use crate::progress::Observer;
+
+/// Outcome of one file of a batch.
+enum Status {
+    Optimized {
+        input_format: Format,
+        input_size: u64,
+        output: PathBuf,
+        written: Written,
+    },
+    /// Completed by a previous run according to the journal.
+    Resumed {
+        input_size: u64,
+        output_size: u64,
+        output: PathBuf,
+    },
+    /// Not an image in a supported format.
+    Skipped,
+}
+
+/// Settings of a batch.
+pub struct Batch<'a> {
+    settings: Settings<'a>,
+    /// Output directory mirroring the inputs, or `None` to overwrite the inputs.
+    output_dir: Option<&'a Path>,
+    output_format: Option<&'a str>,
+    journal: Option<Journal>,
+    /// Hash of the settings recorded in the journal.
+    fingerprint: String,
+    /// Whether a JSON report line is written for each file.
+    report: bool,
+    /// Whether the outputs are only projected without writing them.
+    dry_run: bool,
+    /// Observer of the search of each input.
+    observer: fn(&Path) -> Arc<dyn Observer>,
+}
+
+impl<'a> Batch<'a> {
+    pub fn new(
+        settings: Settings<'a>,
+        output_dir: Option<&'a Path>,
+        output_format: Option<&'a str>,
+        journal: Option<Journal>,
+        report: bool,
+        dry_run: bool,
+        observer: fn(&Path) -> Arc<dyn Observer>,
+    ) -> Self {
+        Self {
+            fingerprint: settings.fingerprint(output_format),
+            settings,
+            output_dir,
+            output_format,
+            journal,
+            report,
+            dry_run,
+            observer,
+        }
+    }
+
+    /// Optimize `files` using `jobs` threads, print status of each file and summary of the whole
+    /// batch. Files are given with their paths relative to the output directory.
+    pub fn run(mut self, files: &[(PathBuf, PathBuf)], jobs: usize) -> Result<(), String> {
+        // Thread budget is split between images processed concurrently and threads used by the
+        // search and the codecs of each image. Images are independent, so they get the threads
+        // first.
+        let images = jobs.min(files.len()).max(1);
+        let threads = jobs / images;
+        self.settings.options.jobs = threads;
+
+        // Each worker has its own pool for decoding and compressing one image at a time.
+        let image_pools = (0..images)
+            .map(|_| {
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .map_err(|err| err.to_string())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let batch = &self;
+        // Workers take files from a shared queue in input order.
+        let next = AtomicUsize::new(0);
+        let statuses = Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            for image_pool in &image_pools {
+                let (next, statuses) = (&next, &statuses);
+                scope.spawn(move || loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    let (path, relative) = match files.get(i) {
+                        Some(file) => file,
+                        None => break,
+                    };
+                    let status = image_pool.install(|| batch.optimize_file(path, relative));
+                    match &status {
+                        Ok(Status::Optimized {
+                            input_size,
+                            written,
+                            ..
+                        }) => log::info!(
+                            "{}: {} -> {} bytes  {:>3} % of original{}",
+                            path.display(),
+                            input_size,
+                            written.size,
+                            100 * written.size as u64 / input_size,
+                            if batch.dry_run {
+                                format!("  {}", describe_choice(&batch.settings, written))
+                            } else {
+                                String::new()
+                            }
+                        ),
+                        Ok(Status::Resumed { .. }) => {
+                            log::info!("{}: already optimized", path.display())
+                        }
+                        Ok(Status::Skipped) => {
+                            log::info!("{}: skipped, unknown format", path.display())
+                        }
+                        Err(err) => log::error!("{}: {}", path.display(), err),
+                    }
+                    if batch.report {
+                        println!("{}", batch.report_file(path, &status));
+                    }
+                    statuses.lock().unwrap().push((i, status));
+                });
+            }
+        });
+
+        let (mut optimized, mut resumed, mut skipped, mut failed) =
+            (0usize, 0usize, 0usize, 0usize);
+        let (mut input_bytes, mut output_bytes) = (0, 0);
+        // Number of files with each choice and files whose output isn't smaller than the input.
+        let mut choices = BTreeMap::new();
+        let mut not_smaller = Vec::new();
+        let mut statuses = statuses.into_inner().unwrap();
+        statuses.sort_by_key(|(i, _)| *i);
+        for (i, status) in statuses {
+            match status {
+                Ok(Status::Optimized {
+                    input_size,
+                    written,
+                    ..
+                }) => {
+                    optimized += 1;
+                    input_bytes += input_size;
+                    output_bytes += written.size as u64;
+                    *choices.entry(Choice::of(&written)).or_insert(0usize) += 1;
+                    if written.kept_input || written.size as u64 >= input_size {
+                        not_smaller.push(&files[i].0);
+                    }
+                }
+                Ok(Status::Resumed {
+                    input_size,
+                    output_size,
+                    ..
+                }) => {
+                    resumed += 1;
+                    input_bytes += input_size;
+                    output_bytes += output_size;
+                }
+                Ok(Status::Skipped) => skipped += 1,
+                Err(_) => failed += 1,
+            }
+        }
+
+        if self.dry_run {
+            if !choices.is_empty() {
+                log::info!(
+                    "chosen {}",
+                    choices
+                        .iter()
+                        .map(|(choice, count)| format!("{}: {}", choice, count))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+            for path in &not_smaller {
+                log::info!("{}: output isn't smaller than input", path.display());
+            }
+        }
+        log::info!(
+            "{} optimized, {} resumed, {} skipped, {} failed, {} {} bytes{}",
+            optimized,
+            resumed,
+            skipped,
+            failed,
+            if self.dry_run { "would save" } else { "saved" },
+            input_bytes as i64 - output_bytes as i64,
+            match (100 * output_bytes).checked_div(input_bytes) {
+                Some(percent) => format!("  {:>3} % of original", percent),
+                None => String::new(),
+            }
+        );
+
+        if self.report {
+            let savings = input_bytes as i64 - output_bytes as i64;
+            let summary = json!({
+                "optimized": optimized,
+                "resumed": resumed,
+                "skipped": skipped,
+                "failed": failed,
+                "input_size": input_bytes,
+                "output_size": output_bytes,
+                "savings": savings,
+                "savings_percent": match input_bytes {
+                    0 => None,
+                    _ => Some(100.0 * savings as f64 / input_bytes as f64),
+                },
+                "dry_run": self.dry_run,
+                "choices": choices
+                    .iter()
+                    .map(|(choice, &count)| (choice.key(), count.into()))
+                    .collect::<Map<_, _>>(),
+                "not_smaller": not_smaller
+                    .iter()
+                    .map(|path| path.to_string_lossy())
+                    .collect::<Vec<_>>(),
+            });
+            println!("{}", json!({ "summary": summary }));
+        }
+
+        if failed > 0 {
+            Err(format!("failed to optimize {} of the files", failed))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Batch<'_> {
+    /// Optimize file at `path` and write it to `relative` under the output directory, or
+    /// overwrite it if there's no output directory.
+    fn optimize_file(&self, path: &Path, relative: &Path) -> Result<Status, String> {
+        let start = Instant::now();
+        let input_buffer =
+            std::fs::read(path).map_err(|err| format!("failed to read input: {}", err))?;
+        let read_time = start.elapsed();
+        let input_format = match Format::from_magic(&input_buffer) {
+            Some(format) => format,
+            None => return Ok(Status::Skipped),
+        };
+        let input_hash = format!("{:x}", Sha256::digest(&input_buffer));
+
+        if let Some(entry) = self
+            .journal
+            .as_ref()
+            .and_then(|journal| journal.completed(path, &self.fingerprint, &input_hash))
+        {
+            return Ok(Status::Resumed {
+                input_size: entry.input_size,
+                output_size: entry.output_size,
+                output: entry.output.clone(),
+            });
+        }
+
+        let output_format = match self.output_format {
+            Some(format) => parse_format(format),
+            None => Some(input_format),
+        };
+
+        let output_path = match self.output_dir {
+            Some(output_dir) => {
+                let mut output_path = output_dir.join(relative);
+                // Keep the original extension unless the format changes.
+                match output_format {
+                    Some(format) if format != input_format => {
+                        output_path.set_extension(format.ext());
+                    }
+                    _ => {}
+                }
+                output_path
+            }
+            None => path.to_path_buf(),
+        };
+        let destination = match (self.output_dir, output_format) {
+            _ if self.dry_run => Destination::None,
+            (Some(_), output_format) => {
+                if let Some(parent) = output_path.parent() {
+                    std::fs::create_dir_all(parent)
+                        .map_err(|err| format!("failed to create output directory: {}", err))?;
+                }
+                match output_format {
+                    None => Destination::Path(output_path.clone()),
+                    Some(_) => Destination::Output(
+                        Output::write_file(&output_path)
+                            .map_err(|err| format!("failed to open output file: {}", err))?,
+                    ),
+                }
+            }
+            (None, _) => Destination::Output(
+                Output::overwrite_file(path)
+                    .map_err(|err| format!("unable to overwrite file: {}", err))?,
+            ),
+        };
+
+        // Searches of concurrent inputs are reported separately, so that their lines aren't mixed.
+        let observer = (self.observer)(path);
+        let mut written = optimize_file(
+            &self.settings,
+            &observer,
+            input_format,
+            &input_buffer,
+            output_format,
+            destination,
+        )?;
+        written.timings.insert(0, ("read", read_time));
+        let output_path = match (self.output_dir, output_format) {
+            (Some(_), None) => output_path.with_extension(written.format.ext()),
+            _ => output_path,
+        };
+
+        let input_size = input_buffer.len() as u64;
+        let output_size = written.size as u64;
+        if let Some(journal) = &self.journal {
+            let entry = Entry {
+                input_hash,
+                output_hash: written.hash.clone(),
+                settings: self.fingerprint.clone(),
+                input_size,
+                output_size,
+                output: output_path.clone(),
+            };
+            journal
+                .record(path, &entry)
+                .map_err(|err| format!("failed to write journal: {}", err))?;
+        }
+
+        Ok(Status::Optimized {
+            input_format,
+            input_size,
+            output: output_path,
+            written,
+        })
+    }
+
+    /// JSON line reporting `status` of file at `path`.
+    fn report_file(&self, path: &Path, status: &Result<Status, String>) -> Value {
+        let input = path.to_string_lossy().into_owned();
+        match status {
+            Ok(Status::Optimized {
+                input_format,
+                input_size,
+                output,
+                written,
+            }) => {
+                let mut report = Map::new();
+                report.insert("status".to_string(), "optimized".into());
+                if let Value::Object(fields) = report_file(
+                    &self.settings,
+                    Some(path),
+                    Some(output),
+                    *input_format,
+                    *input_size as usize,
+                    written,
+                ) {
+                    report.extend(fields);
+                }
+                Value::Object(report)
+            }
+            Ok(Status::Resumed {
+                input_size,
+                output_size,
+                output,
+            }) => json!({
+                "status": "resumed",
+                "input": input,
+                "output": output.to_string_lossy(),
+                "input_size": input_size,
+                "output_size": output_size,
+            }),
+            Ok(Status::Skipped) => json!({ "status": "skipped", "input": input }),
+            Err(err) => json!({ "status": "failed", "input": input, "error": err }),
+        }
+    }
+}
+
+/// Whether `input` is a glob pattern instead of a path.
+pub fn is_pattern(input: &OsStr) -> bool {
+    !Path::new(input).exists()
+        && input
+            .to_str()
+            .map(|input| input.contains(&['*', '?', '['][..]))
+            .unwrap_or(false)
+}
+
+/// Expand inputs to files and their paths relative to the input, which are mirrored in the output
+/// directory. Directories are searched recursively and glob patterns are expanded relative to
+/// the directory before the first wildcard.
+pub fn collect_inputs(inputs: &[&OsStr]) -> Result<Vec<(PathBuf, PathBuf)>, String> {
+    let mut files = Vec::new();
+    for &input in inputs {
+        let path = Path::new(input);
+        let (root, paths) = if path.is_dir() {
+            (path.to_path_buf(), vec![path.to_path_buf()])
+        } else if is_pattern(input) {
+            let pattern = input.to_str().unwrap();
+            let root = path
+                .components()
+                .take_while(|component| !is_pattern(component.as_os_str()))
+                .collect::<PathBuf>();
+            let paths = glob::glob(pattern)
+                .map_err(|err| format!("invalid pattern {}: {}", pattern, err))?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|err| format!("failed to read input directory: {}", err))?;
+            (root, paths)
+        } else {
+            let root = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+            (root, vec![path.to_path_buf()])
+        };
+        for file in collect_files(paths.into_iter())
+            .map_err(|err| format!("failed to read input directory: {}", err))?
+            .into_iter()
+            .filter(|file| !is_temporary_file(file))
+        {
+            let relative = file.strip_prefix(&root).unwrap_or(&file).to_path_buf();
+            files.push((file, relative));
+        }
+    }
+    Ok(files)
+}
+
+// Collect files from the given paths. Directories are searched recursively.
+pub fn collect_files(paths: impl Iterator<Item = PathBuf>) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let mut entries = std::fs::read_dir(&path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+            entries.sort();
+            files.extend(collect_files(entries.into_iter())?);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn collects_inputs_relative_to_their_root() {
+        let dir = tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        for name in &["a/1.png", "a/b/2.png", "a/b/3.jpeg"] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+
+        let root = dir.path().join("a");
+        let files = collect_inputs(&[root.as_os_str()]).unwrap();
+        let relative: Vec<_> = files.iter().map(|(_, relative)| relative.clone()).collect();
+        assert_eq!(
+            relative,
+            vec![
+                PathBuf::from("1.png"),
+                PathBuf::from("b/2.png"),
+                PathBuf::from("b/3.jpeg")
+            ]
+        );
+
+        let pattern = dir.path().join("a/*/*.png");
+        let files = collect_inputs(&[pattern.as_os_str()]).unwrap();
+        assert_eq!(
+            files,
+            vec![(dir.path().join("a/b/2.png"), PathBuf::from("b/2.png"))]
+        );
+    }
+}
@@ -0,0 +1,79 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use std::ptr;
+
+use jpegxl_sys::butteraugli::*;
+use jpegxl_sys::{JxlDataType, JxlEndianness, JxlPixelFormat};
+
+use crate::common::Image;
+use crate::metric::Metric;
+
+const PIXEL_FORMAT: JxlPixelFormat = JxlPixelFormat {
+    num_channels: 4,
+    data_type: JxlDataType::Uint8,
+    endianness: JxlEndianness::Native,
+    align: 0,
+};
+
+pub struct Calculator {
+    api: *mut JxlButteraugliApi,
+    original: Image,
+}
+
+// The API object only holds comparison settings and isn't modified by comparisons, so it can be
+// shared between threads.
+unsafe impl Send for Calculator {}
+unsafe impl Sync for Calculator {}
+
+impl Calculator {
+    pub fn new(original: &Image) -> Option<Self> {
+        let api = unsafe { JxlButteraugliApiCreate(ptr::null()) };
+        if api.is_null() {
+            return None;
+        }
+        Some(Self {
+            api,
+            original: original.clone(),
+        })
+    }
+
+    /// Butteraugli distance using 3-norm which is less sensitive to single outliers than the
+    /// maximum distance.
+    pub fn compare(&self, compressed: &Image) -> Option<f64> {
+        if compressed.width != self.original.width || compressed.height != self.original.height {
+            return None;
+        }
+        unsafe {
+            let result = JxlButteraugliCompute(
+                self.api,
+                self.original.width as u32,
+                self.original.height as u32,
+                &PIXEL_FORMAT,
+                self.original.as_bytes().as_ptr() as *const _,
+                self.original.as_bytes().len(),
+                &PIXEL_FORMAT,
+                compressed.as_bytes().as_ptr() as *const _,
+                compressed.as_bytes().len(),
+            );
+            if result.is_null() {
+                return None;
+            }
+            let distance = JxlButteraugliResultGetDistance(result, 3.0);
+            JxlButteraugliResultDestroy(result);
+            Some(distance.into())
+        }
+    }
+}
+
+impl Metric for Calculator {
+    fn compare(&self, compressed: &Image) -> Option<f64> {
+        self.compare(compressed)
+    }
+}
+
+impl Drop for Calculator {
+    fn drop(&mut self) {
+        unsafe { JxlButteraugliApiDestroy(self.api) }
+    }
+}
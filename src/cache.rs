@@ -0,0 +1,258 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use sha2::{Digest, Sha256};
+
+use crate::common::Format;
+
+/// Versions of pio and libwebp, which can be linked dynamically and thus change without changing
+/// pio. Versions of the other encoders are determined by the version of pio.
+pub fn encoder_versions() -> String {
+    let webp = unsafe { libwebp_sys::WebPGetEncoderVersion() };
+    format!(
+        "pio {}; libwebp {}.{}.{}",
+        env!("CARGO_PKG_VERSION"),
+        (webp >> 16) & 0xff,
+        (webp >> 8) & 0xff,
+        webp & 0xff,
+    )
+}
+
+/// Age after which a temporary file is assumed to be left behind by an interrupted write rather
+/// than being written by another process.
+const TEMPORARY_FILE_GRACE: Duration = Duration::from_secs(60 * 60);
+
+/// Whether `name` is a key returned by `Cache::key`.
+fn is_key(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Whether `name` is the name of an entry written by `Cache::put`.
+fn is_entry(name: &str) -> bool {
+    match name.split_once('.') {
+        Some((key, ext)) => is_key(key) && Format::ALL.iter().any(|format| format.ext() == ext),
+        None => false,
+    }
+}
+
+/// Whether `name` is the name of a temporary file created by `Cache::put`.
+fn is_temporary_file(name: &str) -> bool {
+    let parts = name
+        .strip_prefix('.')
+        .and_then(|name| name.strip_suffix(".tmp"))
+        .and_then(|name| {
+            let (key, name) = name.split_once('.')?;
+            let (pid, thread) = name.split_once('.')?;
+            Some((
+                key,
+                pid,
+                thread.strip_prefix("ThreadId(")?.strip_suffix(')')?,
+            ))
+        });
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|c| c.is_ascii_digit());
+    match parts {
+        Some((key, pid, thread)) => is_key(key) && digits(pid) && digits(thread),
+        None => false,
+    }
+}
+
+/// Output found in the cache.
+pub struct Cached {
+    pub format: Format,
+    pub buffer: Vec<u8>,
+}
+
+/// Outcome of pruning the cache.
+pub struct Pruned {
+    pub removed_files: usize,
+    pub removed_bytes: u64,
+    pub remaining_bytes: u64,
+}
+
+/// On-disk cache of optimized outputs addressed by the hash of the input and the settings used to
+/// optimize it. Each entry is a file named by the key with the extension of the output format.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    /// Open cache in `dir`, creating the directory if needed.
+    pub fn open(dir: impl AsRef<Path>) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    /// Key of `input` optimized with settings described by `fingerprint`. Encoder versions are
+    /// included, so upgrading an encoder invalidates its entries.
+    pub fn key(input: &[u8], fingerprint: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(format!("{:x}", Sha256::digest(input)));
+        hasher.update(b"\n");
+        hasher.update(fingerprint);
+        hasher.update(b"\n");
+        hasher.update(encoder_versions());
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn path(&self, key: &str, format: Format) -> PathBuf {
+        self.dir.join(format!("{}.{}", key, format.ext()))
+    }
+
+    /// Cached output for `key`. The entry is marked as used, so that it's pruned last.
+    pub fn get(&self, key: &str) -> Option<Cached> {
+        Format::ALL.iter().find_map(|&format| {
+            let path = self.path(key, format);
+            let buffer = std::fs::read(&path).ok()?;
+            if let Ok(file) = File::options().write(true).open(&path) {
+                file.set_modified(SystemTime::now()).unwrap_or(());
+            }
+            Some(Cached { format, buffer })
+        })
+    }
+
+    /// Store output for `key`. The entry is written to a temporary file first, so that readers
+    /// never see a partial entry.
+    pub fn put(&self, key: &str, format: Format, buffer: &[u8]) -> std::io::Result<()> {
+        let path = self.path(key, format);
+        let tmp_path = self.dir.join(format!(
+            ".{}.{}.{:?}.tmp",
+            key,
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        let result = File::create(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(buffer)?;
+                file.sync_all()
+            })
+            .and_then(|_| std::fs::rename(&tmp_path, &path));
+        if result.is_err() {
+            std::fs::remove_file(&tmp_path).unwrap_or(());
+        }
+        result
+    }
+
+    /// Remove least recently used entries until the total size of the cache is at most
+    /// `max_size` bytes. Temporary files left behind by interrupted writes are removed too. Other
+    /// files in the directory are neither counted nor removed.
+    pub fn prune(&self, max_size: u64) -> std::io::Result<Pruned> {
+        let mut entries = Vec::new();
+        let mut pruned = Pruned {
+            removed_files: 0,
+            removed_bytes: 0,
+            remaining_bytes: 0,
+        };
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let path = entry.path();
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name,
+                None => continue,
+            };
+            let modified = metadata.modified()?;
+            if is_temporary_file(name) {
+                // Newer files may still be written by another process.
+                let stale = SystemTime::now()
+                    .duration_since(modified)
+                    .is_ok_and(|age| age >= TEMPORARY_FILE_GRACE);
+                if stale {
+                    std::fs::remove_file(&path)?;
+                    pruned.removed_files += 1;
+                    pruned.removed_bytes += metadata.len();
+                }
+                continue;
+            }
+            if !is_entry(name) {
+                continue;
+            }
+            pruned.remaining_bytes += metadata.len();
+            entries.push((modified, metadata.len(), path));
+        }
+
+        entries.sort();
+        for (_, size, path) in entries {
+            if pruned.remaining_bytes <= max_size {
+                break;
+            }
+            std::fs::remove_file(&path)?;
+            pruned.removed_files += 1;
+            pruned.removed_bytes += size;
+            pruned.remaining_bytes -= size;
+        }
+        Ok(pruned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `size` bytes to `name` in `dir` and set its modification time `age` in the past.
+    fn write(dir: &Path, name: &str, size: usize, age: Duration) -> PathBuf {
+        let path = dir.join(name);
+        let file = File::create(&path).unwrap();
+        file.set_len(size as u64).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+        path
+    }
+
+    #[test]
+    fn recognizes_file_names() {
+        let key = Cache::key(b"input", "settings");
+        assert!(is_entry(&format!("{}.jpeg", key)));
+        assert!(!is_entry(&format!("{}.txt", key)));
+        assert!(!is_entry(&format!("{}.jpeg", &key[1..])));
+        assert!(!is_entry(&format!("{}.jpeg", key.to_uppercase())));
+        assert!(!is_entry("notes.jpeg"));
+
+        let tmp = format!(".{}.{}.{:?}.tmp", key, 1234, std::thread::current().id());
+        assert!(is_temporary_file(&tmp));
+        assert!(!is_temporary_file(&format!(".{}.tmp", key)));
+        assert!(!is_temporary_file(".swap.tmp"));
+    }
+
+    #[test]
+    fn prunes_only_own_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::open(dir.path()).unwrap();
+        let hour = Duration::from_secs(60 * 60);
+        let old = write(
+            dir.path(),
+            &format!("{}.png", "a".repeat(64)),
+            100,
+            2 * hour,
+        );
+        let new = write(dir.path(), &format!("{}.jpeg", "b".repeat(64)), 100, hour);
+        let other = write(dir.path(), "photo.jpeg", 1000, 3 * hour);
+        let stale = write(
+            dir.path(),
+            &format!(".{}.1.ThreadId(1).tmp", "c".repeat(64)),
+            10,
+            2 * hour,
+        );
+        let fresh = write(
+            dir.path(),
+            &format!(".{}.2.ThreadId(1).tmp", "d".repeat(64)),
+            10,
+            Duration::from_secs(1),
+        );
+
+        let pruned = cache.prune(150).unwrap();
+        assert_eq!(pruned.removed_files, 2);
+        assert_eq!(pruned.removed_bytes, 110);
+        assert_eq!(pruned.remaining_bytes, 100);
+        assert!(!old.exists() && !stale.exists());
+        assert!(new.exists() && other.exists() && fresh.exists());
+    }
+}
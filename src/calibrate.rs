@@ -0,0 +1,150 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::common::{ChromaSubsampling, Format, Image};
+use crate::metric::MetricKind;
+use crate::optimize::compressors;
+use crate::quality::QualityTable;
+use rgb::RGB8;
+
+/// Collects metric scores of every quality over a corpus of images to build a quality table.
+pub struct Calibration {
+    format: Format,
+    chroma_subsampling: ChromaSubsampling,
+    metric: MetricKind,
+    scores: Vec<Vec<f64>>,
+}
+
+impl Calibration {
+    pub fn new(format: Format, chroma_subsampling: ChromaSubsampling, metric: MetricKind) -> Self {
+        Self {
+            format,
+            chroma_subsampling,
+            metric,
+            scores: vec![Vec::new(); 101],
+        }
+    }
+
+    /// Compress `image` with qualities 0-100 and record score of each. Transparent images are
+    /// blended on white, the default background, if the format doesn't support transparency.
+    pub fn add(&mut self, image: &Image) -> Result<(), String> {
+        let mut image = image.clone();
+        if !self.format.supports_transparency() {
+            image.alpha_blend(RGB8::new(255, 255, 255));
+        }
+        let image = &image;
+        let (lossy_compress, _) = compressors(self.format);
+        let attr = self
+            .metric
+            .prepare(image)
+            .ok_or_else(|| "Failed to prepare metric".to_string())?;
+        for quality in 0..=100 {
+            let (compressed, _buffer) = lossy_compress(image, quality, self.chroma_subsampling)?;
+            let score = attr
+                .compare(&compressed)
+                .ok_or_else(|| "Failed to compare images".to_string())?;
+            self.scores[quality as usize].push(score);
+        }
+        Ok(())
+    }
+
+    /// Number of images added so far.
+    pub fn len(&self) -> usize {
+        self.scores[0].len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Quality table with median score of each quality or `None` if no images were added. A
+    /// median that is further from the perfect score than the median of a lower quality is
+    /// replaced by the latter, so that higher quality never means a looser target.
+    pub fn table(&self) -> Option<QualityTable> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut values: Vec<f64> = self
+            .scores
+            .iter()
+            .map(|scores| {
+                let mut scores = scores.clone();
+                scores.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                let n = scores.len();
+                if n % 2 == 1 {
+                    scores[n / 2]
+                } else {
+                    (scores[n / 2 - 1] + scores[n / 2]) / 2.0
+                }
+            })
+            .collect();
+        for quality in 1..values.len() {
+            if self.metric.distance(values[quality]) > self.metric.distance(values[quality - 1]) {
+                values[quality] = values[quality - 1];
+            }
+        }
+        Some(QualityTable::new(self.metric, values))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimize::decode;
+
+    fn image() -> Image {
+        decode(
+            Format::PNG,
+            &std::fs::read("images/image1-original.png").unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn targets(table: &QualityTable) -> Vec<f64> {
+        (0..=100).map(|quality| table.target(quality)).collect()
+    }
+
+    #[test]
+    fn blends_transparent_images_without_transparency_support() {
+        let mut transparent = image();
+        for pixel in transparent.data.iter_mut().step_by(2) {
+            pixel.a = 0;
+        }
+        let mut blended = transparent.clone();
+        blended.alpha_blend(RGB8::new(255, 255, 255));
+
+        let mut tables = Vec::new();
+        for image in &[transparent, blended] {
+            let mut calibration =
+                Calibration::new(Format::JPEG, ChromaSubsampling::_420, MetricKind::Dssim);
+            calibration.add(image).unwrap();
+            tables.push(targets(&calibration.table().unwrap()));
+        }
+        assert_eq!(tables[0], tables[1]);
+    }
+
+    #[test]
+    fn makes_table_monotonic_in_distance() {
+        for (metric, perfect) in &[(MetricKind::Dssim, 0.0), (MetricKind::Ssimulacra2, 100.0)] {
+            let mut calibration = Calibration::new(Format::JPEG, ChromaSubsampling::_420, *metric);
+            // Distances 100 - quality with a bump at quality 50.
+            for quality in 0..=100 {
+                let distance = match quality {
+                    50 => 60.0,
+                    _ => 100.0 - quality as f64,
+                };
+                let score = match metric {
+                    MetricKind::Dssim => distance,
+                    _ => perfect - distance,
+                };
+                calibration.scores[quality].push(score);
+            }
+            let distances = targets(&calibration.table().unwrap())
+                .into_iter()
+                .map(|score| metric.distance(score))
+                .collect::<Vec<_>>();
+            assert_eq!(distances[50], 51.0);
+            assert!(distances.windows(2).all(|pair| pair[1] <= pair[0]));
+        }
+    }
+}
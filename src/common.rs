@@ -2,6 +2,7 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 use std::ffi::OsStr;
+use std::fmt;
 use std::path::Path;
 
 use dssim::{ToRGBAPLU, RGBAPLU};
@@ -37,7 +38,7 @@ fn is_gray(color: RGB8) -> bool {
     distance(color.r, color.g) <= 1 && distance(color.g, color.b) <= 1
 }
 
-fn srgb_to_linear(u: u8) -> f32 {
+pub(crate) fn srgb_to_linear(u: u8) -> f32 {
     let u = u as f32 / 255.0;
     if u <= 0.04045 {
         u / 12.92
@@ -46,7 +47,7 @@ fn srgb_to_linear(u: u8) -> f32 {
     }
 }
 
-fn linear_to_srgb(u: f32) -> u8 {
+pub(crate) fn linear_to_srgb(u: f32) -> u8 {
     if u <= 0.0031308 {
         (255.0 * (12.92 * u)).round() as u8
     } else {
@@ -232,6 +233,16 @@ pub enum ChromaSubsampling {
     _444,
 }
 
+impl fmt::Display for ChromaSubsampling {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::_420 => "4:2:0",
+            Self::_422 => "4:2:2",
+            Self::_444 => "4:4:4",
+        })
+    }
+}
+
 #[derive(Copy, Clone)]
 pub enum ChromaSubsamplingOption {
     None,
@@ -239,7 +250,7 @@ pub enum ChromaSubsamplingOption {
     Manual(ChromaSubsampling),
 }
 
-#[derive(PartialEq, Copy, Clone)]
+#[derive(PartialEq, Copy, Clone, Debug)]
 pub enum Format {
     JPEG,
     PNG,
@@ -251,6 +262,42 @@ pub enum Format {
 }
 
 impl Format {
+    /// All formats compiled into this build.
+    pub const ALL: &'static [Self] = &[
+        Self::JPEG,
+        Self::PNG,
+        Self::WEBP,
+        #[cfg(feature = "avif")]
+        Self::AVIF,
+        #[cfg(feature = "jxl")]
+        Self::JXL,
+    ];
+
+    pub fn ext(&self) -> &'static str {
+        match self {
+            Self::JPEG => "jpeg",
+            Self::PNG => "png",
+            Self::WEBP => "webp",
+            #[cfg(feature = "avif")]
+            Self::AVIF => "avif",
+            #[cfg(feature = "jxl")]
+            Self::JXL => "jxl",
+        }
+    }
+
+    /// Media type of the format.
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Self::JPEG => "image/jpeg",
+            Self::PNG => "image/png",
+            Self::WEBP => "image/webp",
+            #[cfg(feature = "avif")]
+            Self::AVIF => "image/avif",
+            #[cfg(feature = "jxl")]
+            Self::JXL => "image/jxl",
+        }
+    }
+
     pub fn from_ext(input: &str) -> Option<Self> {
         match input {
             "jpeg" | "jpg" => Some(Self::JPEG),
@@ -314,3 +361,15 @@ impl Format {
 pub type ReadResult = Result<Image, String>;
 pub type CompressResult = Result<(Image, Vec<u8>), String>;
 pub type FastCompressResult = Result<Vec<u8>, String>;
+
+/// Names of the formats that can be read, for error messages.
+pub fn supported_formats() -> String {
+    [
+        "jpeg",
+        "png",
+        "webp",
+        #[cfg(feature = "avif")]
+        "avif",
+    ]
+    .join(", ")
+}
@@ -0,0 +1,438 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Optimizing an image file with the settings of the command-line tool, shared by single inputs,
+//! batches and responsive image sets.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rgb::RGB8;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::cache::Cache;
+use crate::common::{ChromaSubsamplingOption, Format, Image};
+use crate::optimize::{decode, optimize, optimize_auto, Fallback, OptimizeOptions, OptimizeResult};
+use crate::output::Output;
+use crate::progress::Observer;
+use crate::resize::{resize, ResizeOptions};
+
+// Output format is `None` when it's selected automatically.
+pub fn parse_format(format: &str) -> Option<Format> {
+    match format {
+        "auto" => None,
+        format => Some(Format::from_ext(format).unwrap()),
+    }
+}
+
+/// Settings shared by all inputs.
+pub struct Settings<'a> {
+    /// Options of the search. Output format, chroma subsampling and size budget are set for each
+    /// input separately.
+    pub options: OptimizeOptions,
+    pub chroma_subsampling: ChromaSubsamplingOption,
+    pub max_bpp: Option<f64>,
+    /// Formats to try when the output format is selected automatically.
+    pub formats: Vec<Format>,
+    pub no_transparency: bool,
+    pub background_color: RGB8,
+    pub resize: ResizeOptions,
+    pub fail_strategy: &'a str,
+    pub cache: Option<Cache>,
+}
+
+impl Settings<'_> {
+    /// Hash of the settings that affect the output when the output format is given by
+    /// `output_format`.
+    pub fn fingerprint(&self, output_format: Option<&str>) -> String {
+        let settings = format!(
+            "{}; output format {}; formats {:?}; max bpp {:?}; no transparency {}; \
+             background color {:?}; resize {:?}; optimization failed {}",
+            self.options.fingerprint(),
+            output_format.unwrap_or("input"),
+            self.formats,
+            self.max_bpp,
+            self.no_transparency,
+            self.background_color,
+            self.resize,
+            self.fail_strategy,
+        );
+        format!("{:x}", Sha256::digest(settings))
+    }
+}
+
+/// Where the optimized image is written.
+pub enum Destination {
+    /// Output opened before the optimization.
+    Output(Output),
+    /// File whose extension is set after the output format has been selected automatically.
+    Path(PathBuf),
+    /// Nothing is written in a dry run.
+    None,
+}
+
+/// Output written by `optimize_file`.
+pub struct Written {
+    /// Format of the written data, which is the input format if the input was kept.
+    pub format: Format,
+    pub size: usize,
+    /// SHA-256 of the written data.
+    pub hash: String,
+    /// Dimensions of the input image. Unknown if the output was found in the cache.
+    pub dimensions: Option<(usize, usize)>,
+    /// Target score in the output format.
+    pub target: Option<f64>,
+    /// Outcome of the search without the compressed image, or `None` if the output was found in
+    /// the cache.
+    pub result: Option<OptimizeResult>,
+    /// Whether the input was written as is.
+    pub kept_input: bool,
+    /// Time spent in each stage of the optimization.
+    pub timings: Vec<(&'static str, Duration)>,
+}
+
+/// What was chosen for an input, for the summary of a dry run.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Choice {
+    Quality(u8),
+    Lossless,
+    /// Input was kept as is.
+    Original,
+    /// Output was found in the cache, so the choice is unknown.
+    Cached,
+}
+
+impl Choice {
+    pub fn of(written: &Written) -> Self {
+        match &written.result {
+            _ if written.kept_input => Self::Original,
+            None => Self::Cached,
+            Some(result) => match result.quality {
+                Some(quality) => Self::Quality(quality),
+                None => Self::Lossless,
+            },
+        }
+    }
+
+    /// Key of the choice in the JSON report.
+    pub fn key(&self) -> String {
+        match self {
+            Self::Quality(quality) => quality.to_string(),
+            Self::Lossless => "lossless".to_string(),
+            Self::Original => "original".to_string(),
+            Self::Cached => "cached".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Choice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Quality(quality) => write!(f, "quality {}", quality),
+            _ => f.write_str(&self.key()),
+        }
+    }
+}
+
+/// Choice and its score, for example `quality 75  0.002755 SSIM`.
+pub fn describe_choice(settings: &Settings, written: &Written) -> String {
+    let choice = Choice::of(written);
+    match (&written.result, choice) {
+        (Some(result), Choice::Quality(_)) | (Some(result), Choice::Lossless) => format!(
+            "{}  {:.6} {}",
+            choice,
+            result.score,
+            settings.options.metric.label()
+        ),
+        _ => choice.to_string(),
+    }
+}
+
+/// Optimize image in `input_buffer` and write it to `destination`, reporting the search to
+/// `observer`.
+pub fn optimize_file(
+    settings: &Settings,
+    observer: &Arc<dyn Observer>,
+    input_format: Format,
+    input_buffer: &[u8],
+    output_format: Option<Format>,
+    destination: Destination,
+) -> Result<Written, String> {
+    let original_size = input_buffer.len();
+    let dry_run = matches!(destination, Destination::None);
+
+    // Cached output of the same input with the same settings is used without decoding the input.
+    let cache_key = settings.cache.as_ref().map(|_| {
+        let output_format = output_format.map_or("auto", |format| format.ext());
+        Cache::key(input_buffer, &settings.fingerprint(Some(output_format)))
+    });
+    if let (Some(cache), Some(key)) = (&settings.cache, &cache_key) {
+        if let Some(cached) = cache.get(key) {
+            log::info!("using cached output");
+            let start = Instant::now();
+            let mut written = write_output(destination, &cached.buffer, cached.format)?;
+            written.timings.push(("write", start.elapsed()));
+            return Ok(written);
+        }
+    }
+
+    let start = Instant::now();
+    let mut input_image = decode(input_format, input_buffer)
+        .map_err(|err| format!("failed to read input: {}", err))?;
+    // Input can't be written as is if it has been resized.
+    let resized = match resize(&input_image, &settings.resize) {
+        Some(image) => {
+            log::debug!(
+                "resized {}x{} to {}x{}",
+                input_image.width,
+                input_image.height,
+                image.width,
+                image.height
+            );
+            input_image = image;
+            true
+        }
+        None => false,
+    };
+
+    let supports_transparency = match output_format {
+        Some(format) => format.supports_transparency(),
+        None => true,
+    };
+    if !supports_transparency || settings.no_transparency {
+        input_image.alpha_blend(settings.background_color);
+    }
+    let decode_time = start.elapsed();
+
+    let start = Instant::now();
+    let (options, mut result) = search(
+        settings,
+        observer,
+        &input_image,
+        output_format,
+        original_size,
+        resized,
+    )?;
+    let search_time = start.elapsed();
+
+    // Input is written as is if the target couldn't be met in strict mode or if the output would
+    // be larger than the input and the user asked to copy the input in that case. It can't be
+    // written in place of output of another format.
+    let converted = output_format.is_some_and(|format| format != input_format);
+    let keep_input = if result.original && resized {
+        return Err(
+            "target can't be met and the input can't be kept because the image was resized"
+                .to_string(),
+        );
+    } else if result.original && converted {
+        return Err(format!(
+            "target can't be met and the input can't be kept because it isn't {}",
+            result.format.ext()
+        ));
+    } else if result.original {
+        true
+    } else if result.buffer.len() <= original_size {
+        false
+    } else {
+        match settings.fail_strategy {
+            "none" => {
+                log::warn!("Output is larger than input but still writing output normally. This behavior can be changed with `--optimization-failed` option.");
+                false
+            }
+            "exit" => {
+                return Err("error: Output would be larger than input, exiting now...".to_string())
+            }
+            "copy" if resized => {
+                log::warn!("Output is larger than input but the image was resized, writing output normally...");
+                false
+            }
+            "copy" if converted => {
+                return Err(format!(
+                    "Output would be larger than input, but the input can't be copied because it isn't {}",
+                    result.format.ext()
+                ))
+            }
+            "copy" => {
+                log::warn!("Output would be larger than input, copying input to output...");
+                true
+            }
+            _ => unreachable!(),
+        }
+    };
+    let (buffer, format) = if keep_input {
+        (input_buffer, input_format)
+    } else {
+        (&result.buffer[..], result.format)
+    };
+
+    let start = Instant::now();
+    let mut written = write_output(destination, buffer, format)?;
+    let write_time = start.elapsed();
+    if let (Some(cache), Some(key), false) = (&settings.cache, &cache_key, dry_run) {
+        cache
+            .put(key, format, buffer)
+            .unwrap_or_else(|err| log::warn!("failed to write to cache: {}", err));
+    }
+
+    written.dimensions = Some((input_image.width, input_image.height));
+    written.target = options.score(options.target, result.format).ok();
+    written.kept_input = keep_input;
+    written.timings = vec![
+        ("decode", decode_time),
+        ("search", search_time),
+        ("write", write_time),
+    ];
+    log::debug!(
+        "decoded in {:?}, searched in {:?}, wrote in {:?}",
+        decode_time,
+        search_time,
+        write_time
+    );
+    result.buffer = Vec::new();
+    written.result = Some(result);
+    Ok(written)
+}
+
+/// Search for the output of `image` in `output_format`, or in the best of the formats of
+/// `settings` if it's `None`, reporting it to `observer`. Returns the options used in the search
+/// and the result.
+pub fn search(
+    settings: &Settings,
+    observer: &Arc<dyn Observer>,
+    image: &Image,
+    output_format: Option<Format>,
+    original_size: usize,
+    resized: bool,
+) -> Result<(OptimizeOptions, OptimizeResult), String> {
+    let mut options = settings.options.clone();
+    options.observer = observer.clone();
+    options.format = output_format.unwrap_or(Format::JPEG);
+    options.chroma_subsampling = match output_format {
+        Some(format) if !format.supports_chroma_subsampling() => ChromaSubsamplingOption::None,
+        _ => settings.chroma_subsampling,
+    };
+    if resized && options.strict == Some(Fallback::Original) {
+        options.strict = Some(Fallback::Lossless);
+    }
+    if let Some(bpp) = settings.max_bpp {
+        options.max_size = Some((bpp * (image.width * image.height) as f64 / 8.0) as u64);
+    }
+
+    let result = match output_format {
+        Some(_) => optimize(image, &options, original_size as u64),
+        None => optimize_auto(image, &settings.formats, &options, original_size as u64).map(
+            |(result, sizes)| {
+                for (format, size) in sizes {
+                    match size {
+                        Ok(size) => log::info!(
+                            "{:>4}: {} bytes  {:>3} % of original",
+                            format.ext(),
+                            size,
+                            100 * size / original_size
+                        ),
+                        Err(err) => log::info!("{:>4}: {}", format.ext(), err),
+                    }
+                }
+                log::info!("selected {}", result.format.ext());
+                result
+            },
+        ),
+    };
+    let result = result.map_err(|err| format!("failed to compress image: {}", err))?;
+    Ok((options, result))
+}
+
+pub fn write_output(
+    destination: Destination,
+    buffer: &[u8],
+    format: Format,
+) -> Result<Written, String> {
+    let output_writer = match destination {
+        Destination::Output(output) => Some(output),
+        Destination::Path(path) => Some(
+            Output::write_file(path.with_extension(format.ext()))
+                .map_err(|err| format!("failed to open output file: {}", err))?,
+        ),
+        Destination::None => None,
+    };
+    if let Some(output_writer) = output_writer {
+        output_writer
+            .write(buffer)
+            .map_err(|err| format!("failed to write output: {}", err))?;
+    }
+
+    Ok(Written {
+        format,
+        size: buffer.len(),
+        hash: format!("{:x}", Sha256::digest(buffer)),
+        dimensions: None,
+        target: None,
+        result: None,
+        kept_input: false,
+        timings: Vec::new(),
+    })
+}
+
+/// Report of optimizing `input` into `output` for `--report json`.
+pub fn report_file(
+    settings: &Settings,
+    input: Option<&Path>,
+    output: Option<&Path>,
+    input_format: Format,
+    input_size: usize,
+    written: &Written,
+) -> Value {
+    let metric = settings.options.metric;
+    let result = written.result.as_ref();
+    let savings = input_size as i64 - written.size as i64;
+    let attempts = result
+        .map(|result| &result.attempts[..])
+        .unwrap_or(&[])
+        .iter()
+        .map(|attempt| {
+            json!({
+                "format": attempt.format.ext(),
+                "quality": attempt.quality,
+                "chroma_subsampling": attempt
+                    .chroma_subsampling
+                    .map(|sampling| sampling.to_string()),
+                "score": attempt.score,
+                "region": attempt.region,
+                "size": attempt.size,
+                "fast": attempt.fast,
+            })
+        })
+        .collect::<Vec<_>>();
+    let timings = written
+        .timings
+        .iter()
+        .map(|(stage, time)| (stage.to_string(), (time.as_secs_f64() * 1000.0).into()))
+        .collect::<Map<_, _>>();
+    json!({
+        "input": input.map(|path| path.to_string_lossy()),
+        "output": output.map(|path| path.to_string_lossy()),
+        "input_format": input_format.ext(),
+        "output_format": written.format.ext(),
+        "width": written.dimensions.map(|(width, _)| width),
+        "height": written.dimensions.map(|(_, height)| height),
+        "input_size": input_size,
+        "output_size": written.size,
+        "savings": savings,
+        "savings_percent": 100.0 * savings as f64 / input_size as f64,
+        "metric": metric.name(),
+        "target": written.target,
+        "quality": result.and_then(|result| result.quality),
+        "score": result.map(|result| result.score),
+        "chroma_subsampling": result
+            .and_then(|result| result.chroma_subsampling)
+            .map(|sampling| sampling.to_string()),
+        "lossless": result.map(|result| result.lossless),
+        "kept_input": written.kept_input,
+        "cached": result.is_none(),
+        "attempts": attempts,
+        "timings_ms": timings,
+    })
+}
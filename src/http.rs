@@ -0,0 +1,508 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Minimal HTTP/1.1 server for running pio as a local service. Each connection serves a single
+//! request and is closed after the response.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// Time a client may stay idle while sending a request or receiving a response.
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// Maximum length of the request line and of each header line.
+const MAX_LINE: u64 = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+
+pub struct Request {
+    pub method: String,
+    /// Path without the query string.
+    pub path: String,
+    /// Decoded query parameters in the order of the request.
+    pub query: Vec<(String, String)>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Value of header `name`, which is compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Value of query parameter `name`.
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body: body.into(),
+        }
+    }
+
+    /// Plain text response explaining an error.
+    pub fn error(status: u16, message: &str) -> Self {
+        Self::new(
+            status,
+            "text/plain; charset=utf-8",
+            format!("{}\n", message),
+        )
+    }
+
+    pub fn with_header(mut self, name: &str, value: impl ToString) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            head += &format!("{}: {}\r\n", name, value);
+        }
+        head += &format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.body.len()
+        );
+        writer.write_all(head.as_bytes())?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Entity",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+/// Read line ending in CRLF or LF without the line ending.
+fn read_line(reader: &mut impl BufRead) -> Result<String, Response> {
+    let mut line = Vec::new();
+    reader
+        .take(MAX_LINE)
+        .read_until(b'\n', &mut line)
+        .map_err(|err| Response::error(400, &format!("failed to read request: {}", err)))?;
+    if !line.ends_with(b"\n") {
+        return Err(Response::error(431, "request line or header is too long"));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|_| Response::error(400, "request isn't valid UTF-8"))
+}
+
+/// Decode `%XX` escapes and `+` in a query string component.
+pub fn percent_decode(input: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut input = input.bytes();
+    while let Some(byte) = input.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = [input.next(), input.next()];
+                let decoded = match hex {
+                    [Some(high), Some(low)] => std::str::from_utf8(&[high, low])
+                        .ok()
+                        .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+                    _ => None,
+                };
+                match decoded {
+                    Some(byte) => bytes.push(byte),
+                    None => {
+                        bytes.push(b'%');
+                        bytes.extend(hex.iter().flatten());
+                    }
+                }
+            }
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Read request from `reader`. Bodies larger than `max_body` are refused. `Expect:
+/// 100-continue` is answered through `writer` before the body is read.
+pub fn read_request(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+    max_body: usize,
+) -> Result<Request, Response> {
+    let request_line = read_line(reader)?;
+    let mut parts = request_line.split(' ');
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+            (method.to_string(), target)
+        }
+        _ => return Err(Response::error(400, "invalid request line")),
+    };
+    let (path, query) = match target.find('?') {
+        Some(i) => (&target[..i], &target[i + 1..]),
+        None => (target, ""),
+    };
+    let query = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| match param.find('=') {
+            Some(i) => (percent_decode(&param[..i]), percent_decode(&param[i + 1..])),
+            None => (percent_decode(param), String::new()),
+        })
+        .collect();
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(Response::error(431, "too many headers"));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| Response::error(400, "invalid header"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    let mut request = Request {
+        method,
+        path: path.to_string(),
+        query,
+        headers,
+        body: Vec::new(),
+    };
+
+    if request.header("Transfer-Encoding").is_some() {
+        return Err(Response::error(
+            411,
+            "chunked requests aren't supported, send Content-Length",
+        ));
+    }
+    let length = match request.header("Content-Length") {
+        Some(length) => length
+            .parse::<usize>()
+            .map_err(|_| Response::error(400, "invalid Content-Length"))?,
+        None => 0,
+    };
+    if length > max_body {
+        return Err(Response::error(
+            413,
+            &format!("body is larger than {} bytes", max_body),
+        ));
+    }
+    if length > 0 {
+        let expects_continue = request
+            .header("Expect")
+            .map(|expect| expect.eq_ignore_ascii_case("100-continue"))
+            .unwrap_or(false);
+        if expects_continue {
+            writer
+                .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                .map_err(|err| Response::error(400, &err.to_string()))?;
+        }
+        // The body is read into a growing buffer instead of allocating `length` bytes up front,
+        // so that a client can't reserve memory without sending the data.
+        reader
+            .take(length as u64)
+            .read_to_end(&mut request.body)
+            .map_err(|err| Response::error(400, &format!("failed to read body: {}", err)))?;
+        if request.body.len() < length {
+            return Err(Response::error(
+                400,
+                "failed to read body: connection closed",
+            ));
+        }
+    }
+    Ok(request)
+}
+
+/// Media ranges of an `Accept` header and their weights. Ranges are lowercased and parameters
+/// other than `q` are ignored.
+pub fn media_ranges(accept: &str) -> Vec<(String, f32)> {
+    accept
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let media = params.next()?.trim().to_ascii_lowercase();
+            if media.is_empty() {
+                return None;
+            }
+            let weight = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse().ok())
+                .unwrap_or(1.0);
+            Some((media, weight))
+        })
+        .collect()
+}
+
+/// Weight of `mime` in `ranges` given by the most specific matching range, and whether the range
+/// names `mime` exactly instead of matching it by a wildcard. `None` if no range matches.
+pub fn accepts(ranges: &[(String, f32)], mime: &str) -> Option<(f32, bool)> {
+    let main_type = mime.split('/').next().unwrap_or("");
+    ranges
+        .iter()
+        .filter_map(|(range, weight)| {
+            let specificity = if range == mime {
+                2
+            } else if range.strip_suffix("/*") == Some(main_type) {
+                1
+            } else if range == "*/*" {
+                0
+            } else {
+                return None;
+            };
+            Some((specificity, *weight))
+        })
+        .max_by_key(|(specificity, _)| *specificity)
+        .map(|(specificity, weight)| (weight, specificity == 2))
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    max_body: usize,
+    handler: &(dyn Fn(&Request) -> Response + Send + Sync),
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let response = match read_request(&mut reader, &mut writer, max_body) {
+        Ok(request) => handler(&request),
+        Err(response) => response,
+    };
+    response.write_to(&mut writer)
+}
+
+/// Number of open connections, which is limited by waiting until one of them is closed.
+struct Connections {
+    open: Mutex<usize>,
+    closed: Condvar,
+}
+
+impl Connections {
+    /// Wait until fewer than `max` connections are open and count a new one, which is closed
+    /// when the returned guard is dropped.
+    fn open(self: &Arc<Self>, max: usize) -> Connection {
+        let mut open = self.open.lock().unwrap();
+        while *open >= max {
+            open = self.closed.wait(open).unwrap();
+        }
+        *open += 1;
+        Connection(Arc::clone(self))
+    }
+}
+
+/// Open connection counted in `Connections`.
+struct Connection(Arc<Connections>);
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        *self.0.open.lock().unwrap() -= 1;
+        self.0.closed.notify_one();
+    }
+}
+
+/// Answer connections to `listener` with `handler`. Each connection is handled on its own thread,
+/// so `handler` is responsible for limiting expensive work. At most `max_connections` are handled
+/// at a time and further connections wait in the backlog of the listener.
+pub fn serve(
+    listener: TcpListener,
+    max_connections: usize,
+    max_body: usize,
+    handler: impl Fn(&Request) -> Response + Send + Sync + 'static,
+) -> std::io::Result<()> {
+    let handler: Arc<dyn Fn(&Request) -> Response + Send + Sync> = Arc::new(handler);
+    let connections = Arc::new(Connections {
+        open: Mutex::new(0),
+        closed: Condvar::new(),
+    });
+    loop {
+        let connection = connections.open(max_connections);
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                log::warn!("failed to accept connection: {}", err);
+                continue;
+            }
+        };
+        let handler = Arc::clone(&handler);
+        std::thread::spawn(move || {
+            let _connection = connection;
+            if let Err(err) = handle_connection(stream, max_body, handler.as_ref()) {
+                log::debug!("failed to answer request: {}", err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(input: &[u8], max_body: usize) -> (Result<Request, Response>, Vec<u8>) {
+        let mut written = Vec::new();
+        let request = read_request(&mut &input[..], &mut written, max_body);
+        (request, written)
+    }
+
+    fn status(result: Result<Request, Response>) -> u16 {
+        match result {
+            Ok(_) => panic!("request was accepted"),
+            Err(response) => response.status,
+        }
+    }
+
+    #[test]
+    fn reads_request() {
+        let (request, written) = read(
+            b"POST /optimize?format=webp&quality=80&name=a%20b+c&flag HTTP/1.1\r\n\
+              Host: localhost\r\n\
+              content-length:  4 \r\n\
+              \n\
+              data",
+            10,
+        );
+        let request = request.ok().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/optimize");
+        assert_eq!(request.query("format"), Some("webp"));
+        assert_eq!(request.query("quality"), Some("80"));
+        assert_eq!(request.query("name"), Some("a b c"));
+        assert_eq!(request.query("flag"), Some(""));
+        assert_eq!(request.query("missing"), None);
+        assert_eq!(request.header("Content-Length"), Some("4"));
+        assert_eq!(request.header("HOST"), Some("localhost"));
+        assert_eq!(request.body, b"data");
+        assert!(written.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        assert_eq!(status(read(b"GET /\r\n\r\n", 0).0), 400);
+        assert_eq!(status(read(b"GET / SPDY/3\r\n\r\n", 0).0), 400);
+        assert_eq!(status(read(b"GET / HTTP/1.1\r\nHost\r\n\r\n", 0).0), 400);
+
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE as usize));
+        assert_eq!(status(read(long.as_bytes(), 0).0), 431);
+        let many = format!(
+            "GET / HTTP/1.1\r\n{}\r\n",
+            "A: b\r\n".repeat(MAX_HEADERS + 1)
+        );
+        assert_eq!(status(read(many.as_bytes(), 0).0), 431);
+    }
+
+    #[test]
+    fn checks_body_length() {
+        let request =
+            |length: &str| format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\nab", length);
+        assert_eq!(read(request("2").as_bytes(), 2).0.ok().unwrap().body, b"ab");
+        assert_eq!(status(read(request("3").as_bytes(), 3).0), 400);
+        assert_eq!(status(read(request("3").as_bytes(), 2).0), 413);
+        assert_eq!(status(read(request("-1").as_bytes(), 2).0), 400);
+        // A huge length within the limit isn't allocated before the body arrives.
+        assert_eq!(
+            status(read(request("1000000000000").as_bytes(), usize::MAX).0),
+            400
+        );
+
+        let chunked = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(status(read(chunked, 2).0), 411);
+    }
+
+    #[test]
+    fn answers_expect_continue() {
+        let (request, written) = read(
+            b"POST / HTTP/1.1\r\nContent-Length: 2\r\nExpect: 100-Continue\r\n\r\nab",
+            2,
+        );
+        assert_eq!(request.ok().unwrap().body, b"ab");
+        assert_eq!(written, b"HTTP/1.1 100 Continue\r\n\r\n");
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("a+b%20c"), "a b c");
+        assert_eq!(percent_decode("%2b%2B%3d"), "++=");
+        assert_eq!(percent_decode("%C3%A4"), "\u{e4}");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert_eq!(percent_decode("%ff"), "\u{fffd}");
+    }
+
+    #[test]
+    fn weighs_media_ranges() {
+        let ranges = media_ranges("image/WebP;q=0.5, image/*; q=0.8 ,*/*;level=1;q=0, text/html");
+        assert_eq!(
+            ranges,
+            vec![
+                ("image/webp".to_string(), 0.5),
+                ("image/*".to_string(), 0.8),
+                ("*/*".to_string(), 0.0),
+                ("text/html".to_string(), 1.0),
+            ]
+        );
+        assert_eq!(accepts(&ranges, "image/webp"), Some((0.5, true)));
+        assert_eq!(accepts(&ranges, "image/avif"), Some((0.8, false)));
+        assert_eq!(accepts(&ranges, "application/json"), Some((0.0, false)));
+        assert_eq!(accepts(&media_ranges("image/png"), "image/jpeg"), None);
+        assert_eq!(media_ranges(" , ;q=1"), vec![]);
+        assert_eq!(
+            media_ranges("image/png;q=high"),
+            vec![("image/png".to_string(), 1.0)]
+        );
+    }
+
+    #[test]
+    fn limits_open_connections() {
+        let connections = Arc::new(Connections {
+            open: Mutex::new(0),
+            closed: Condvar::new(),
+        });
+        let first = connections.open(2);
+        let _second = connections.open(2);
+        let waiting = {
+            let connections = Arc::clone(&connections);
+            std::thread::spawn(move || {
+                connections.open(2);
+            })
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!waiting.is_finished());
+        drop(first);
+        waiting.join().unwrap();
+        assert_eq!(*connections.open.lock().unwrap(), 1);
+    }
+}
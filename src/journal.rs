@@ -0,0 +1,228 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::output::remove_temporary_files;
+
+const HEADER: &str = "\
+# Journal of completed inputs written by pio. Columns are separated by tabs:
+# input hash, output hash, settings hash, input size, output size, output path, input path
+";
+
+/// Completed input recorded in the journal.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    /// SHA-256 of the input before the optimization.
+    pub input_hash: String,
+    /// SHA-256 of the written output.
+    pub output_hash: String,
+    /// Hash of the settings used to optimize the input.
+    pub settings: String,
+    pub input_size: u64,
+    pub output_size: u64,
+    pub output: PathBuf,
+}
+
+/// Append-only record of inputs completed by a batch. Each entry is written to disk as soon as
+/// the input is completed, so a run that was interrupted can be resumed from the journal.
+pub struct Journal {
+    file: Mutex<File>,
+    entries: HashMap<PathBuf, Entry>,
+}
+
+impl Journal {
+    /// Open journal at `path`. If `resume` is true, existing entries are loaded and new ones are
+    /// appended, otherwise the journal is started from scratch.
+    pub fn open(path: impl AsRef<Path>, resume: bool) -> Result<Self, String> {
+        let path = path.as_ref();
+        let mut entries = HashMap::new();
+        if resume {
+            match std::fs::read_to_string(path) {
+                Ok(input) => {
+                    for (i, line) in input.lines().enumerate() {
+                        if line.is_empty() || line.starts_with('#') {
+                            continue;
+                        }
+                        let (input, entry) = parse_line(line)
+                            .ok_or_else(|| format!("invalid journal on line {}", i + 1))?;
+                        entries.insert(input, entry);
+                    }
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(format!("failed to read journal: {}", err)),
+            }
+        }
+
+        let mut options = OpenOptions::new();
+        if resume {
+            options.append(true).create(true);
+        } else {
+            options.write(true).create(true).truncate(true);
+        }
+        let mut file = options
+            .open(path)
+            .map_err(|err| format!("failed to open journal: {}", err))?;
+        if file.metadata().map(|metadata| metadata.len()).unwrap_or(0) == 0 {
+            file.write_all(HEADER.as_bytes())
+                .map_err(|err| format!("failed to write journal: {}", err))?;
+        }
+
+        Ok(Self {
+            file: Mutex::new(file),
+            entries,
+        })
+    }
+
+    /// Entry of `input` recorded in a previous run.
+    pub fn get(&self, input: impl AsRef<Path>) -> Option<&Entry> {
+        self.entries.get(input.as_ref())
+    }
+
+    /// Entry of `input` if a previous run completed it with `settings` and its output still
+    /// exists. Input that was optimized in place has the hash of the output.
+    pub fn completed(
+        &self,
+        input: impl AsRef<Path>,
+        settings: &str,
+        input_hash: &str,
+    ) -> Option<&Entry> {
+        self.get(input).filter(|entry| {
+            entry.settings == settings
+                && (entry.input_hash == input_hash || entry.output_hash == input_hash)
+                && entry.output.is_file()
+        })
+    }
+
+    /// Append completed `input` to the journal and flush it to disk.
+    pub fn record(&self, input: impl AsRef<Path>, entry: &Entry) -> std::io::Result<()> {
+        let line = format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            entry.input_hash,
+            entry.output_hash,
+            entry.settings,
+            entry.input_size,
+            entry.output_size,
+            escape(&entry.output),
+            escape(input.as_ref()),
+        );
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())?;
+        file.sync_data()
+    }
+}
+
+/// Remove temporary files left behind next to `inputs` by a run that was killed while
+/// overwriting them.
+pub fn remove_leftovers<'a>(inputs: impl IntoIterator<Item = &'a Path>) -> Result<(), String> {
+    let dirs = inputs
+        .into_iter()
+        .map(|path| match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        })
+        .collect::<BTreeSet<_>>();
+    for dir in dirs {
+        let removed = remove_temporary_files(dir).map_err(|err| {
+            format!(
+                "failed to remove temporary files from {}: {}",
+                dir.display(),
+                err
+            )
+        })?;
+        if removed > 0 {
+            log::info!("removed {} temporary files from {}", removed, dir.display());
+        }
+    }
+    Ok(())
+}
+
+fn parse_line(line: &str) -> Option<(PathBuf, Entry)> {
+    let mut columns = line.split('\t');
+    let mut next = || columns.next();
+    let entry = Entry {
+        input_hash: next()?.to_string(),
+        output_hash: next()?.to_string(),
+        settings: next()?.to_string(),
+        input_size: next()?.parse().ok()?,
+        output_size: next()?.parse().ok()?,
+        output: unescape(next()?)?,
+    };
+    let input = unescape(next()?)?;
+    Some((input, entry))
+}
+
+/// Escape backslashes, tabs and line breaks in `path` so that it fits in a column.
+fn escape(path: &Path) -> String {
+    let mut escaped = String::new();
+    for c in path.to_string_lossy().chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(column: &str) -> Option<PathBuf> {
+    let mut path = String::new();
+    let mut chars = column.chars();
+    while let Some(c) = chars.next() {
+        path.push(match c {
+            '\\' => match chars.next()? {
+                '\\' => '\\',
+                't' => '\t',
+                'n' => '\n',
+                'r' => '\r',
+                _ => return None,
+            },
+            c => c,
+        });
+    }
+    Some(PathBuf::from(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn resumes_completed_inputs() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let output = dir.path().join("out\tput.png");
+        std::fs::write(&output, "").unwrap();
+        let entry = Entry {
+            input_hash: "input".to_string(),
+            output_hash: "output".to_string(),
+            settings: "settings".to_string(),
+            input_size: 2,
+            output_size: 1,
+            output: output.clone(),
+        };
+        Journal::open(&path, false)
+            .unwrap()
+            .record("in.png", &entry)
+            .unwrap();
+
+        let journal = Journal::open(&path, true).unwrap();
+        assert_eq!(journal.get("in.png").unwrap().output, output);
+        assert!(journal.completed("in.png", "settings", "input").is_some());
+        // Input was overwritten by the output.
+        assert!(journal.completed("in.png", "settings", "output").is_some());
+        assert!(journal.completed("in.png", "other", "input").is_none());
+        assert!(journal.completed("in.png", "settings", "changed").is_none());
+        std::fs::remove_file(&output).unwrap();
+        assert!(journal.completed("in.png", "settings", "input").is_none());
+
+        assert!(Journal::open(&path, false).unwrap().get("in.png").is_none());
+    }
+}
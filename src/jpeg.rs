@@ -56,13 +56,13 @@ pub fn read(buffer: &[u8]) -> ReadResult {
         Ok(Some(icc)) => match lcms2::Profile::new_icc(&icc) {
             Ok(x) => Some(x),
             Err(err) => {
-                eprintln!("Failed to read ICC profile: {}", err);
+                log::warn!("failed to read ICC profile: {}", err);
                 None
             }
         },
         Ok(None) => None,
         Err(err) => {
-            eprintln!("Failed to read ICC profile: {}", err);
+            log::warn!("failed to read ICC profile: {}", err);
             None
         }
     };
@@ -78,7 +78,7 @@ pub fn read(buffer: &[u8]) -> ReadResult {
 
             if let Some(profile) = profile {
                 if !is_srgb(&profile) {
-                    eprintln!("Transforming RGB to sRGB...");
+                    log::debug!("transforming RGB to sRGB");
                     let transform = lcms2::Transform::new(
                         &profile,
                         lcms2::PixelFormat::RGB_8,
@@ -101,7 +101,7 @@ pub fn read(buffer: &[u8]) -> ReadResult {
 
             if let Some(profile) = profile {
                 if !is_srgb(&profile) {
-                    eprintln!("Transforming Gray to sRGB...");
+                    log::debug!("transforming gray to sRGB");
                     let transform = lcms2::Transform::new(
                         &profile,
                         lcms2::PixelFormat::GRAY_8,
@@ -131,7 +131,7 @@ pub fn read(buffer: &[u8]) -> ReadResult {
                 .ok_or_else(|| "Failed decode image data".to_string())?;
             decompress.finish_decompress();
 
-            eprintln!("Transforming CMYK to sRGB...");
+            log::debug!("transforming CMYK to sRGB");
             let transform = lcms2::Transform::new(
                 &profile,
                 lcms2::PixelFormat::CMYK_8_REV,
@@ -169,12 +169,13 @@ fn compress_base(
         _ => mozjpeg::ColorSpace::JCS_EXT_RGBX,
     });
     cinfo.set_size(image.width, image.height);
-    cinfo.set_quality(quality as f32);
+    // Fastest defaults reset quality, so they must be set first.
     if fast {
         cinfo.set_fastest_defaults();
     } else {
         cinfo.set_use_scans_in_trellis(true);
     }
+    cinfo.set_quality(quality as f32);
     cinfo.set_mem_dest();
 
     if image.color_space != ColorSpace::Gray {
@@ -1,14 +1,32 @@
 // SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+pub mod batch;
+pub mod cache;
+pub mod calibrate;
 pub mod common;
+pub mod file;
+pub mod http;
 pub mod jpeg;
+pub mod journal;
+pub mod metric;
+pub mod optimize;
 pub mod output;
+pub mod picture;
 pub mod png;
 pub mod profile;
+pub mod progress;
+pub mod quality;
+pub mod resize;
+pub mod service;
+pub mod srcset;
 pub mod ssim;
+pub mod ssimulacra2;
 pub mod webp;
+pub mod worker;
 #[cfg(feature = "avif")]
 pub mod avif;
+#[cfg(feature = "butteraugli")]
+pub mod butteraugli;
 #[cfg(feature = "jxl")]
 pub mod jxl;
@@ -3,181 +3,34 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+use std::ffi::OsStr;
+use std::fmt;
 use std::fs::File;
-use std::io::Read;
+use std::io::{IsTerminal, Read, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgGroup, SubCommand};
 use rgb::RGB8;
 
-#[cfg(feature = "avif")]
-use pio::avif;
-#[cfg(feature = "jxl")]
-use pio::jxl;
-
-use pio::common::{ChromaSubsampling, ChromaSubsamplingOption, CompressResult, Format, Image};
+use pio::batch::{collect_files, collect_inputs, is_pattern, Batch};
+use pio::cache::Cache;
+use pio::calibrate::Calibration;
+use pio::common::{supported_formats, ChromaSubsampling, ChromaSubsamplingOption, Format};
+use pio::file::{describe_choice, optimize_file, parse_format, report_file, Destination, Settings};
+use pio::http;
+use pio::journal::{remove_leftovers, Journal};
+use pio::metric::MetricKind;
+use pio::optimize::{decode, Fallback, OptimizeOptions, Target};
 use pio::output::Output;
-use pio::{jpeg, png, ssim, webp};
-
-type LossyCompressor = Box<dyn Fn(&Image, u8, ChromaSubsampling) -> CompressResult>;
-type LosslessCompressor = Box<dyn Fn(&Image) -> CompressResult>;
-
-#[rustfmt::skip]
-const QUALITY_SSIM: [f64; 101] = [
-    0.64405, 0.64405, 0.493921, 0.3717685, 0.2875005, 0.226447, 0.18505, 0.155942,
-    0.13402550000000002, 0.1161245, 0.10214999999999999, 0.09164900000000001, 0.0830645,
-    0.0747825, 0.0686465, 0.0636275, 0.058777499999999996, 0.054973999999999995, 0.0509935,
-    0.048128000000000004, 0.0452685, 0.0428175, 0.0404645, 0.0387125, 0.036169999999999994,
-    0.034700999999999996, 0.03334, 0.0319895, 0.029954, 0.029339499999999998, 0.028261,
-    0.0271415, 0.025916, 0.0248545, 0.0244545, 0.023451, 0.022603, 0.022269, 0.021344, 0.020581,
-    0.0202495, 0.019450000000000002, 0.019161499999999998, 0.0189065, 0.018063, 0.017832,
-    0.0169555, 0.016857999999999998, 0.016676, 0.0159105, 0.0157275, 0.015555,
-    0.014891499999999998, 0.014727, 0.0145845, 0.013921, 0.0137565, 0.0135065, 0.012928,
-    0.012669, 0.0125305, 0.011922499999999999, 0.011724, 0.011544, 0.0112675, 0.0107825,
-    0.010481, 0.010245, 0.009772, 0.0095075, 0.009262, 0.008721, 0.0084715, 0.008324999999999999,
-    0.007556500000000001, 0.0074540000000000006, 0.007243, 0.0067735, 0.0066254999999999994,
-    0.006356499999999999, 0.005924499999999999, 0.005674500000000001, 0.005422, 0.0050215,
-    0.0047565, 0.0044755, 0.0041294999999999995, 0.0038510000000000003, 0.00361, 0.003372,
-    0.0029255, 0.0027010000000000003, 0.0024415, 0.002091, 0.0017955, 0.001591, 0.001218,
-    0.0009805, 0.000749, 0.000548, 0.0004,
-];
-
-fn find_image(
-    image: &Image,
-    attr: &ssim::Calculator,
-    lossy_compress: &LossyCompressor,
-    target: f64,
-    min_quality: u8,
-    max_quality: u8,
-    original_size: u64,
-    chroma_subsampling: ChromaSubsampling,
-) -> Result<(f64, Vec<u8>), String> {
-    let mut min = min_quality;
-    let mut max = max_quality;
-    let mut best_buffer = Vec::new();
-    let mut best_dssim = f64::INFINITY;
-
-    // Compress image with different qualities and find which is closest to the SSIM target. Binary
-    // search is used to speed up the search. Since there are 101 possible quality values, only
-    // ceil(log2(101)) = 7 comparisons are needed at maximum.
-    loop {
-        // Overflow is not possible because `min` and `max` are in range 0-100.
-        let quality = (min + max) / 2;
-
-        let (compressed, buffer) = lossy_compress(image, quality, chroma_subsampling)?;
-
-        for x in 0..=100 / 4 {
-            if x == quality / 4 {
-                eprint!("O")
-            } else if x == 0 || x == 100 / 4 {
-                eprint!("|");
-            } else if x == min / 4 {
-                eprint!("[");
-            } else if x == max / 4 {
-                eprint!("]");
-            } else if x > min / 4 && x < max / 4 {
-                eprint!("-");
-            } else {
-                eprint!(" ");
-            }
-        }
-
-        let dssim = attr
-            .compare(&compressed)
-            .ok_or_else(|| "Failed to calculate SSIM image".to_string())?;
-
-        eprintln!(
-            " {:>3} quality  {:.6} SSIM  {:>3} % of original",
-            quality,
-            dssim,
-            100 * buffer.len() as u64 / original_size,
-        );
-
-        // Last steps of the binary search are pretty close to each other, so the final step may
-        // not actually have SSIM closest to the target. Instead of using the last step, keep track
-        // of the best attempt so far.
-        if (dssim - target).abs() < (best_dssim - target).abs() {
-            best_buffer = buffer;
-            best_dssim = dssim;
-        }
-
-        // Binary search step.
-        if dssim > target {
-            min = quality + 1;
-        } else {
-            // Prevent underflow because comparison is unreliable at low qualities.
-            if quality == 0 {
-                break;
-            }
-            max = quality - 1;
-        }
-
-        if min > max {
-            break;
-        }
-    }
-
-    Ok((best_dssim, best_buffer))
-}
-
-fn compress_image(
-    image: Image,
-    lossy_compress: LossyCompressor,
-    lossless_compress: Option<LosslessCompressor>,
-    target: f64,
-    min_quality: u8,
-    max_quality: u8,
-    original_size: u64,
-    chroma_subsampling: ChromaSubsamplingOption,
-) -> Result<Vec<u8>, String> {
-    let attr = ssim::Calculator::new(&image)
-        .ok_or_else(|| "Failed to calculate SSIM image".to_string())?;
-
-    let mut best_buffer = Vec::new();
-    let mut best_dssim = f64::INFINITY;
-
-    let samplings = match chroma_subsampling {
-        ChromaSubsamplingOption::Auto => vec![
-            ChromaSubsampling::_444,
-            ChromaSubsampling::_422,
-            ChromaSubsampling::_420,
-        ],
-        ChromaSubsamplingOption::Manual(sampling) => vec![sampling],
-        ChromaSubsamplingOption::None => vec![ChromaSubsampling::_444],
-    };
-
-    for sampling in samplings {
-        let (dssim, buffer) = find_image(
-            &image,
-            &attr,
-            &lossy_compress,
-            target,
-            min_quality,
-            max_quality,
-            original_size,
-            sampling,
-        )?;
-        if (dssim - target).abs() < (best_dssim - target).abs() {
-            best_buffer = buffer;
-            best_dssim = dssim;
-        }
-    }
-
-    // Try lossless compression if the format supports it. For example, lossless WebP can sometimes
-    // be smaller than lossy WebP for non-photographic images.
-    if let Some(compress) = lossless_compress {
-        eprint!("|                        |");
-        let (_, b) = compress(&image)?;
-        eprintln!(
-            "    lossless  0.000000 SSIM  {:>3} % of original",
-            100 * b.len() as u64 / original_size
-        );
-        if b.len() < best_buffer.len() {
-            return Ok(b);
-        }
-    }
-
-    Ok(best_buffer)
-}
+use pio::progress::{search_bar, Event, Observer};
+use pio::quality::QualityTable;
+use pio::resize::{Fit, ResizeOptions};
+use pio::service::Service;
+use pio::srcset::write_srcset;
+use pio::worker;
 
 fn validate_quality(x: String) -> Result<(), String> {
     match x.parse::<i8>() {
@@ -205,6 +58,69 @@ fn validate_spread(x: String) -> Result<(), String> {
     }
 }
 
+fn validate_size(x: String) -> Result<(), String> {
+    match x.parse::<u64>() {
+        Ok(x) if x > 0 => Ok(()),
+        _ => Err("expected positive number of bytes".to_string()),
+    }
+}
+
+fn validate_bpp(x: String) -> Result<(), String> {
+    match x.parse::<f64>() {
+        Ok(x) if x > 0.0 && x.is_finite() => Ok(()),
+        _ => Err("expected positive number of bits".to_string()),
+    }
+}
+
+fn validate_tile_size(x: String) -> Result<(), String> {
+    match x.parse::<usize>() {
+        Ok(x) if x > 0 => Ok(()),
+        _ => Err("expected positive number of pixels".to_string()),
+    }
+}
+
+fn validate_encodes(x: String) -> Result<(), String> {
+    match x.parse::<usize>() {
+        Ok(x) if x > 0 => Ok(()),
+        _ => Err("expected positive number of encodes".to_string()),
+    }
+}
+
+fn validate_jobs(x: String) -> Result<(), String> {
+    match x.parse::<usize>() {
+        Ok(x) if x > 0 => Ok(()),
+        _ => Err("expected positive number of threads".to_string()),
+    }
+}
+
+fn validate_concurrency(x: String) -> Result<(), String> {
+    match x.parse::<usize>() {
+        Ok(x) if x > 0 => Ok(()),
+        _ => Err("expected positive number of images".to_string()),
+    }
+}
+
+fn validate_connections(x: String) -> Result<(), String> {
+    match x.parse::<usize>() {
+        Ok(x) if x > 0 => Ok(()),
+        _ => Err("expected positive number of connections".to_string()),
+    }
+}
+
+fn validate_score(x: String) -> Result<(), String> {
+    match x.parse::<f64>() {
+        Ok(x) if x >= 0.0 && x.is_finite() => Ok(()),
+        _ => Err("expected non-negative number".to_string()),
+    }
+}
+
+fn validate_tolerance(x: String) -> Result<(), String> {
+    match x.parse::<f64>() {
+        Ok(x) if x >= 0.0 && x.is_finite() => Ok(()),
+        _ => Err("expected non-negative percentage".to_string()),
+    }
+}
+
 fn parse_color(input: &str) -> Result<RGB8, String> {
     if !input.starts_with('#') {
         return Err("color must start #".to_string());
@@ -219,15 +135,34 @@ fn parse_color(input: &str) -> Result<RGB8, String> {
     ))
 }
 
-fn supported_formats() -> String {
-    [
-        "jpeg",
-        "png",
-        "webp",
-        #[cfg(feature = "avif")]
-        "avif",
-    ]
-    .join(", ")
+/// Parse dimensions given as `WIDTHxHEIGHT`, `WIDTHx`, `xHEIGHT` or `WIDTH`.
+fn parse_dimensions(input: &str) -> Result<(Option<usize>, Option<usize>), String> {
+    let (width, height) = input.split_once('x').unwrap_or((input, ""));
+    let side = |side: &str| match side {
+        "" => Ok(None),
+        side => match side.parse::<usize>() {
+            Ok(side) if side > 0 => Ok(Some(side)),
+            _ => Err("expected dimensions as WIDTHxHEIGHT, WIDTHx or xHEIGHT".to_string()),
+        },
+    };
+    match (side(width)?, side(height)?) {
+        (None, None) => Err("expected dimensions as WIDTHxHEIGHT, WIDTHx or xHEIGHT".to_string()),
+        dimensions => Ok(dimensions),
+    }
+}
+
+fn validate_pixels(x: String) -> Result<(), String> {
+    match x.parse::<usize>() {
+        Ok(x) if x > 0 => Ok(()),
+        _ => Err("expected positive number of pixels".to_string()),
+    }
+}
+
+fn validate_sharpen(x: String) -> Result<(), String> {
+    match x.parse::<f32>() {
+        Ok(x) if x >= 0.0 && x.is_finite() => Ok(()),
+        _ => Err("expected non-negative amount".to_string()),
+    }
 }
 
 fn pio(matches: clap::ArgMatches) -> Result<(), String> {
@@ -235,27 +170,179 @@ fn pio(matches: clap::ArgMatches) -> Result<(), String> {
 
     let spread = matches.value_of("spread").unwrap().parse::<u8>().unwrap();
 
-    let target = QUALITY_SSIM[quality as usize];
-    eprintln!("target ssim: {}", target);
+    // When targeting file size, search the whole quality range unless limited explicitly.
+    let size_budget = matches.is_present("max-size") || matches.is_present("max-bpp");
 
     let min = match matches.value_of("min") {
         Some(s) => s.parse().unwrap(),
+        None if size_budget => 0,
         None => std::cmp::max(0, quality - std::cmp::min(quality, spread)),
     };
     let max = match matches.value_of("max") {
         Some(s) => s.parse().unwrap(),
+        None if size_budget => 100,
         None => std::cmp::min(quality + spread, 100),
     };
     if min > max {
         return Err("min must be smaller or equal to max".to_string());
     }
 
-    let fail_strategy = matches.value_of("optimization-failed").unwrap();
+    let quality_table = match matches.value_of_os("quality-table") {
+        Some(path) => Some(
+            QualityTable::load(path)
+                .map_err(|err| format!("failed to read quality table: {}", err))?,
+        ),
+        None => None,
+    };
+
+    let metric = MetricKind::from_name(matches.value_of("metric").unwrap()).unwrap();
+
+    let chroma_subsampling = match matches.value_of("chroma-subsampling").unwrap() {
+        "420" => ChromaSubsamplingOption::Manual(ChromaSubsampling::_420),
+        "422" => ChromaSubsamplingOption::Manual(ChromaSubsampling::_422),
+        "444" => ChromaSubsamplingOption::Manual(ChromaSubsampling::_444),
+        "auto" => ChromaSubsamplingOption::Auto,
+        _ => unreachable!(),
+    };
+
+    let options = OptimizeOptions {
+        target: match matches.value_of("target") {
+            Some(score) => Target::Score(score.parse().unwrap()),
+            None => Target::Quality(quality),
+        },
+        min_quality: min,
+        max_quality: max,
+        chroma_subsampling,
+        format: Format::JPEG,
+        metric,
+        lossless: true,
+        max_size: matches
+            .value_of("max-size")
+            .map(|bytes| bytes.parse().unwrap()),
+        floor: matches
+            .value_of("floor")
+            .map(|floor| Target::Quality(floor.parse().unwrap())),
+        quality_table,
+        max_region: matches
+            .value_of("max-region")
+            .map(|score| score.parse().unwrap()),
+        tile_size: matches.value_of("tile-size").unwrap().parse().unwrap(),
+        fast_search: !matches.is_present("no-fast-search"),
+        max_encodes: matches.value_of("max-encodes").unwrap().parse().unwrap(),
+        jobs: match matches.value_of("jobs") {
+            Some(jobs) => jobs.parse().unwrap(),
+            None => 0,
+        },
+        tolerance: matches
+            .value_of("tolerance")
+            .unwrap()
+            .parse::<f64>()
+            .unwrap()
+            / 100.0,
+        strict: if matches.is_present("strict") {
+            match matches.value_of("fallback").unwrap() {
+                "lossless" => Some(Fallback::Lossless),
+                "original" => Some(Fallback::Original),
+                _ => unreachable!(),
+            }
+        } else {
+            None
+        },
+        observer: Arc::new(SearchObserver::new(None)),
+    };
+
+    let settings = Settings {
+        options,
+        chroma_subsampling,
+        max_bpp: match matches.value_of("max-size") {
+            Some(_) => None,
+            None => matches.value_of("max-bpp").map(|bpp| bpp.parse().unwrap()),
+        },
+        formats: match matches.values_of("formats") {
+            Some(formats) => formats.map(|f| Format::from_ext(f).unwrap()).collect(),
+            None => Format::ALL.to_vec(),
+        },
+        no_transparency: matches.is_present("no-transparency"),
+        background_color: parse_color(matches.value_of("background-color").unwrap()).unwrap(),
+        resize: {
+            let (width, height) = match matches.value_of("resize") {
+                Some(dimensions) => parse_dimensions(dimensions).unwrap(),
+                None => (None, None),
+            };
+            ResizeOptions {
+                width,
+                height,
+                fit: Fit::from_name(matches.value_of("fit").unwrap()).unwrap(),
+                max_width: matches.value_of("max-width").map(|x| x.parse().unwrap()),
+                max_height: matches.value_of("max-height").map(|x| x.parse().unwrap()),
+                sharpen: matches
+                    .value_of("sharpen")
+                    .map_or(0.0, |amount| amount.parse().unwrap()),
+            }
+        },
+        fail_strategy: matches.value_of("optimization-failed").unwrap(),
+        cache: match matches.value_of_os("cache") {
+            Some(dir) => {
+                Some(Cache::open(dir).map_err(|err| format!("failed to open cache: {}", err))?)
+            }
+            None => None,
+        },
+    };
+
+    let inputs: Vec<&OsStr> = match matches.values_of_os("INPUT") {
+        Some(inputs) => inputs.collect(),
+        None => Vec::new(),
+    };
+    let batch = inputs.len() > 1
+        || matches.is_present("output-dir")
+        || matches.is_present("journal")
+        || inputs
+            .iter()
+            .any(|input| Path::new(input).is_dir() || is_pattern(input));
+    let result = if matches.is_present("widths") {
+        optimize_srcset(&matches, settings, &inputs)
+    } else if batch {
+        optimize_batch(&matches, settings, &inputs)
+    } else {
+        optimize_single(&matches, settings, &inputs)
+    };
+
+    // Limit size of the cache after the run, so that the outputs of this run are kept.
+    if let (Some(dir), Some(max_size), false) = (
+        matches.value_of_os("cache"),
+        matches.value_of("cache-size"),
+        matches.is_present("dry-run"),
+    ) {
+        prune_cache(dir, max_size.parse().unwrap())?;
+    }
+
+    result
+}
+
+fn optimize_single(
+    matches: &clap::ArgMatches,
+    settings: Settings,
+    inputs: &[&OsStr],
+) -> Result<(), String> {
+    let report = matches.is_present("report");
+    let dry_run = matches.is_present("dry-run");
+    if report
+        && !dry_run
+        && matches.value_of_os("output").is_none()
+        && !matches.is_present("in-place")
+    {
+        return Err(
+            "report is written to standard output, use `--output` to write the image to a file"
+                .to_string(),
+        );
+    }
 
+    let start = Instant::now();
     let (input_format, input_buffer) = {
-        let mut reader: Box<dyn std::io::Read> = match matches.value_of_os("INPUT") {
+        let mut reader: Box<dyn std::io::Read> = match inputs.first() {
             None => {
-                if matches.value_of("output").is_none()
+                if !dry_run
+                    && matches.value_of("output").is_none()
                     && matches.value_of("output-format").is_none()
                 {
                     return Err("reading from standard input, use `--output` to write to a file or `--output-format` to write to standard output".to_string());
@@ -285,133 +372,437 @@ fn pio(matches: clap::ArgMatches) -> Result<(), String> {
 
         (fmt, buf)
     };
+    let read_time = start.elapsed();
 
-    let (output_format, output_writer) = if matches.is_present("in-place") {
+    let (output_format, destination) = if dry_run {
+        let format = match (
+            matches.value_of("output-format"),
+            matches.value_of_os("output"),
+        ) {
+            (Some(format), _) => parse_format(format),
+            (None, Some(path)) => Some(Format::from_path(path).ok_or_else(|| {
+                "failed to determine output format, specify it using `--output-format`".to_string()
+            })?),
+            (None, None) => Some(input_format),
+        };
+        (format, Destination::None)
+    } else if matches.is_present("in-place") {
         let format = match matches.value_of("output-format") {
-            Some(format) => Format::from_ext(format).unwrap(),
-            None => input_format,
+            Some(format) => parse_format(format),
+            None => Some(input_format),
         };
         let path = matches.value_of_os("INPUT").unwrap();
         let output = Output::overwrite_file(path)
             .map_err(|err| format!("unable to overwrite file: {}", err))?;
-        (format, output)
+        (format, Destination::Output(output))
     } else {
         match matches.value_of_os("output") {
             Some(path) => {
                 let format = match matches.value_of("output-format") {
-                    Some(format) => Format::from_ext(format).unwrap(),
-                    None => Format::from_path(path).ok_or_else(|| {
+                    Some(format) => parse_format(format),
+                    None => Some(Format::from_path(path).ok_or_else(|| {
                         format!("failed to determine output format: either use a known file extension ({}) or specify the format using `--output-format`", supported_formats())
-                    })?,
+                    })?),
+                };
+                // File extension of automatically selected format is known only after the
+                // optimization, so the output file is opened later.
+                let destination = match format {
+                    Some(_) => Destination::Output(
+                        Output::write_file(path)
+                            .map_err(|err| format!("failed to open output file: {}", err))?,
+                    ),
+                    None => Destination::Path(PathBuf::from(path)),
                 };
-                let output = Output::write_file(path)
-                    .map_err(|err| format!("failed to open output file: {}", err))?;
-                (format, output)
+                (format, destination)
             }
             None => {
-                let format = Format::from_ext(matches.value_of("output-format").ok_or_else(|| "use `--output` to write to a file or `--output-format` to write to standard output".to_string())?).unwrap();
-                (format, Output::stdout())
+                let format = parse_format(matches.value_of("output-format").ok_or_else(|| "use `--output` to write to a file or `--output-format` to write to standard output".to_string())?);
+                (format, Destination::Output(Output::stdout()))
             }
         }
     };
 
-    let chroma_subsampling = if output_format.supports_chroma_subsampling() {
-        match matches.value_of("chroma-subsampling").unwrap() {
-            "420" => ChromaSubsamplingOption::Manual(ChromaSubsampling::_420),
-            "422" => ChromaSubsamplingOption::Manual(ChromaSubsampling::_422),
-            "444" => ChromaSubsamplingOption::Manual(ChromaSubsampling::_444),
-            "auto" => ChromaSubsamplingOption::Auto,
-            _ => unreachable!(),
-        }
+    let mut written = optimize_file(
+        &settings,
+        &settings.options.observer,
+        input_format,
+        &input_buffer,
+        output_format,
+        destination,
+    )?;
+    written.timings.insert(0, ("read", read_time));
+
+    if dry_run {
+        log::info!(
+            "would write {} bytes  {:>3} % of original  {}",
+            written.size,
+            100 * written.size / input_buffer.len(),
+            describe_choice(&settings, &written)
+        );
+    }
+
+    if report {
+        let output = match matches.value_of_os("output") {
+            Some(path) if output_format.is_none() => {
+                Some(Path::new(path).with_extension(written.format.ext()))
+            }
+            Some(path) => Some(PathBuf::from(path)),
+            None if matches.is_present("in-place") => {
+                Some(PathBuf::from(matches.value_of_os("INPUT").unwrap()))
+            }
+            None => None,
+        };
+        let value = report_file(
+            &settings,
+            inputs.first().map(Path::new),
+            output.as_deref(),
+            input_format,
+            input_buffer.len(),
+            &written,
+        );
+        println!("{}", value);
+    }
+    Ok(())
+}
+
+/// Optimize every image found in `inputs`.
+fn optimize_batch(
+    matches: &clap::ArgMatches,
+    settings: Settings,
+    inputs: &[&OsStr],
+) -> Result<(), String> {
+    let output_dir = matches.value_of_os("output-dir").map(Path::new);
+    let dry_run = matches.is_present("dry-run");
+    if output_dir.is_none() && !matches.is_present("in-place") && !dry_run {
+        return Err("use `--output-dir` or `--in-place` to process multiple inputs".to_string());
+    }
+
+    let files = collect_inputs(inputs)?;
+
+    if matches.is_present("resume") {
+        remove_leftovers(files.iter().map(|(path, _)| path.as_path()))?;
+    }
+
+    let journal = match matches.value_of_os("journal") {
+        Some(path) => Some(Journal::open(path, matches.is_present("resume"))?),
+        None => None,
+    };
+
+    let jobs = match matches.value_of("jobs") {
+        Some(jobs) => jobs.parse().unwrap(),
+        None => num_cpus::get(),
+    };
+    Batch::new(
+        settings,
+        output_dir,
+        matches.value_of("output-format"),
+        journal,
+        matches.is_present("report"),
+        dry_run,
+        |path| Arc::new(SearchObserver::new(Some(path))),
+    )
+    .run(&files, jobs)
+}
+
+/// Write each input at each width given by `--widths` under the output directory.
+fn optimize_srcset(
+    matches: &clap::ArgMatches,
+    settings: Settings,
+    inputs: &[&OsStr],
+) -> Result<(), String> {
+    let output_dir = Path::new(matches.value_of_os("output-dir").unwrap());
+    let mut widths: Vec<usize> = matches
+        .values_of("widths")
+        .unwrap()
+        .map(|width| width.parse().unwrap())
+        .collect();
+    widths.sort_unstable();
+    widths.dedup();
+    let formats: Option<Vec<Format>> = matches
+        .values_of("formats")
+        .map(|formats| formats.map(|f| Format::from_ext(f).unwrap()).collect());
+    let sizes = if matches.is_present("picture") {
+        matches.value_of("sizes")
     } else {
-        ChromaSubsamplingOption::None
+        None
     };
 
-    let original_size = input_buffer.len();
-
-    let mut input_image = match input_format {
-        Format::JPEG => jpeg::read(&input_buffer),
-        Format::PNG => png::read(&input_buffer),
-        Format::WEBP => webp::read(&input_buffer),
-        #[cfg(feature = "avif")]
-        Format::AVIF => avif::read(&input_buffer),
-        #[cfg(feature = "jxl")]
-        Format::JXL => jxl::read(&input_buffer),
-    }
-    .map_err(|err| format!("failed to read input: {}", err))?;
-
-    let (lossy_compress, lossless_compress): (LossyCompressor, Option<LosslessCompressor>) =
-        match output_format {
-            Format::JPEG => (Box::new(jpeg::compress), None),
-            Format::PNG => (Box::new(|img, q, _cs| png::compress(img, q)), None),
-            Format::WEBP => (
-                Box::new(|img, q, _cs| webp::compress(img, q, false)),
-                Some(Box::new(|img| webp::compress(img, 100, true))),
-            ),
-            #[cfg(feature = "avif")]
-            Format::AVIF => (Box::new(|img, q, _cs| avif::compress(img, q)), None),
-            #[cfg(feature = "jxl")]
-            Format::JXL => (
-                Box::new(|img, q, _cs| jxl::compress(img, q, false)),
-                Some(Box::new(|img| jxl::compress(img, 100, true))),
-            ),
+    write_srcset(
+        &settings,
+        output_dir,
+        &collect_inputs(inputs)?,
+        &widths,
+        formats.as_deref(),
+        sizes,
+    )
+}
+
+fn prune_cache(dir: &OsStr, max_size: u64) -> Result<(), String> {
+    let pruned = Cache::open(dir)
+        .and_then(|cache| cache.prune(max_size))
+        .map_err(|err| format!("failed to prune cache: {}", err))?;
+    if pruned.removed_files > 0 {
+        log::info!(
+            "removed {} files ({} bytes) from cache, {} bytes remaining",
+            pruned.removed_files,
+            pruned.removed_bytes,
+            pruned.remaining_bytes
+        );
+    }
+    Ok(())
+}
+
+fn cache(matches: &clap::ArgMatches) -> Result<(), String> {
+    match matches.subcommand() {
+        ("prune", Some(matches)) => prune_cache(
+            matches.value_of_os("DIR").unwrap(),
+            matches.value_of("max-size").unwrap().parse().unwrap(),
+        ),
+        _ => unreachable!(),
+    }
+}
+
+fn serve(matches: &clap::ArgMatches) -> Result<(), String> {
+    let listen = matches.value_of("listen").unwrap();
+    let listener = TcpListener::bind(listen)
+        .map_err(|err| format!("failed to listen on {}: {}", listen, err))?;
+    let address = listener.local_addr().map_err(|err| err.to_string())?;
+
+    let max_concurrency = match matches.value_of("max-concurrency") {
+        Some(max) => max.parse().unwrap(),
+        None => num_cpus::get(),
+    };
+    // Images optimized concurrently share one pool with the whole thread budget, which is built
+    // once instead of for every request.
+    let jobs = match matches.value_of("jobs") {
+        Some(jobs) => jobs.parse().unwrap(),
+        None => num_cpus::get(),
+    };
+    let service = Service::new(max_concurrency, jobs)?;
+    let max_connections = matches
+        .value_of("max-connections")
+        .unwrap()
+        .parse()
+        .unwrap();
+    let max_body = matches.value_of("max-body").unwrap().parse().unwrap();
+
+    log::info!("listening on http://{}", address);
+    http::serve(listener, max_connections, max_body, move |request| {
+        service.handle(request)
+    })
+    .map_err(|err| format!("failed to serve: {}", err))
+}
+
+fn worker(matches: &clap::ArgMatches) -> Result<(), String> {
+    let concurrency = match matches.value_of("max-concurrency") {
+        Some(max) => max.parse().unwrap(),
+        None => num_cpus::get(),
+    };
+    let jobs = match matches.value_of("jobs") {
+        Some(jobs) => jobs.parse().unwrap(),
+        None => num_cpus::get(),
+    };
+    let jobs = (jobs / concurrency).max(1);
+
+    worker::run(
+        std::io::stdin().lock(),
+        std::io::stdout(),
+        concurrency,
+        jobs,
+    )
+}
+
+fn calibrate(matches: &clap::ArgMatches) -> Result<(), String> {
+    let format = Format::from_ext(matches.value_of("format").unwrap()).unwrap();
+    let chroma_subsampling = match matches.value_of("chroma-subsampling").unwrap() {
+        "420" => ChromaSubsampling::_420,
+        "422" => ChromaSubsampling::_422,
+        "444" => ChromaSubsampling::_444,
+        _ => unreachable!(),
+    };
+
+    let files = collect_files(matches.values_of_os("INPUT").unwrap().map(PathBuf::from))
+        .map_err(|err| format!("failed to read input directory: {}", err))?;
+
+    let metric = MetricKind::from_name(matches.value_of("metric").unwrap()).unwrap();
+
+    let mut calibration = Calibration::new(format, chroma_subsampling, metric);
+    for path in files {
+        let buffer = std::fs::read(&path)
+            .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+        let image = match Format::from_magic(&buffer) {
+            Some(input_format) => decode(input_format, &buffer)
+                .map_err(|err| format!("failed to read {}: {}", path.display(), err))?,
+            None => continue,
         };
+        log::info!("{}", path.display());
+        calibration
+            .add(&image)
+            .map_err(|err| format!("failed to compress {}: {}", path.display(), err))?;
+    }
+
+    let table = calibration
+        .table()
+        .ok_or_else(|| "no images found in input".to_string())?;
+
+    let output = match matches.value_of_os("output") {
+        Some(path) => Output::write_file(path)
+            .map_err(|err| format!("failed to open output file: {}", err))?,
+        None => Output::stdout(),
+    };
+    output
+        .write(table.to_string().as_bytes())
+        .map_err(|err| format!("failed to write output: {}", err))
+}
+
+/// Logger writing messages of pio to standard error. Warnings and errors of other crates are
+/// written too.
+struct Logger {
+    level: log::LevelFilter,
+}
 
-    if !output_format.supports_transparency() || matches.is_present("no-transparency") {
-        let bg = parse_color(matches.value_of("background-color").unwrap()).unwrap();
-        input_image.alpha_blend(bg);
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+            && (metadata.level() <= log::Level::Warn || metadata.target().starts_with("pio"))
     }
 
-    match compress_image(
-        input_image,
-        lossy_compress,
-        lossless_compress,
-        target,
-        min,
-        max,
-        original_size as u64,
-        chroma_subsampling,
-    ) {
-        Ok(output_buffer) => {
-            if output_buffer.len() <= original_size as usize {
-                output_writer
-                    .write(&output_buffer)
-                    .map_err(|err| format!("failed to write output: {}", err))?;
-                Ok(())
-            } else {
-                match fail_strategy {
-                    "none" => {
-                        eprintln!("warning: Output is larger than input but still writing output normally. This behavior can be changed with `--optimization-failed` option.");
-                        output_writer
-                            .write(&output_buffer)
-                            .map_err(|err| format!("failed to write output: {}", err))?;
-                        Ok(())
-                    }
-                    "exit" => {
-                        Err("error: Output would be larger than input, exiting now...".to_string())
-                    }
-                    "copy" => {
-                        eprintln!("warning: Output would be larger than input, copying input to output...");
-                        output_writer
-                            .write(&output_buffer)
-                            .map_err(|err| format!("failed to write output: {}", err))?;
-                        Ok(())
-                    }
-                    _ => unreachable!(),
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // Failing to write a message, for example because standard error was closed, isn't
+        // worth failing the work being logged.
+        let mut stderr = std::io::stderr().lock();
+        let _ = match record.level() {
+            log::Level::Error => writeln!(stderr, "error: {}", record.args()),
+            log::Level::Warn => writeln!(stderr, "warning: {}", record.args()),
+            _ => writeln!(stderr, "{}", record.args()),
+        };
+    }
+
+    fn flush(&self) {}
+}
+
+/// Observer showing attempts of the search on a bar of qualities as they're made when standard
+/// error is a terminal. Otherwise attempts are written as plain lines that are easier to process.
+///
+/// Plain lines of concurrent searches are held until the searches finish and then written one
+/// chroma subsampling at a time, so that the output doesn't depend on `--jobs`.
+struct SearchObserver {
+    bar: bool,
+    /// Input whose path starts each line, when several inputs are optimized concurrently.
+    input: Option<PathBuf>,
+    /// Lines of the attempts not yet written and the order of their search.
+    pending: Mutex<Vec<(u8, String)>>,
+}
+
+impl SearchObserver {
+    fn new(input: Option<&Path>) -> Self {
+        Self {
+            bar: std::io::stderr().is_terminal(),
+            input: input.map(Path::to_path_buf),
+            pending: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn write(&self, line: &dyn fmt::Display) {
+        match &self.input {
+            Some(input) => log::info!("{}: {}", input.display(), line),
+            None => log::info!("{}", line),
+        }
+    }
+
+    fn flush(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        // The sort is stable, so attempts of each search stay in the order they were made.
+        pending.sort_by_key(|(order, _)| *order);
+        for (_, line) in pending.drain(..) {
+            self.write(&line);
+        }
+    }
+}
+
+impl Observer for SearchObserver {
+    fn event(&self, event: &Event) {
+        match event {
+            Event::Attempt {
+                attempt,
+                metric,
+                bounds,
+                original_size,
+            } => {
+                if self.bar {
+                    self.write(&search_bar(attempt, *metric, *bounds, *original_size));
+                    return;
                 }
+                let order = match (attempt.quality, attempt.chroma_subsampling) {
+                    (None, _) => 3,
+                    (Some(_), Some(ChromaSubsampling::_422)) => 1,
+                    (Some(_), Some(ChromaSubsampling::_420)) => 2,
+                    (Some(_), _) => 0,
+                };
+                self.pending
+                    .lock()
+                    .unwrap()
+                    .push((order, event.to_string()));
+            }
+            Event::Searched { .. } => self.flush(),
+            event => {
+                self.flush();
+                self.write(event);
             }
         }
-        Err(err) => Err(format!("failed to compress image: {}", err)),
+    }
+}
+
+/// Install logger with the level given by `--quiet` and `--verbose`. They're global, so they can
+/// be given before or after any subcommand.
+fn init_logger(matches: &clap::ArgMatches) {
+    let (mut quiet, mut verbose) = (false, 0);
+    let mut matches = Some(matches);
+    while let Some(m) = matches {
+        quiet |= m.is_present("quiet");
+        verbose = verbose.max(m.occurrences_of("verbose"));
+        matches = m.subcommand().1;
+    }
+    let level = match (quiet, verbose) {
+        (true, _) => log::LevelFilter::Error,
+        (false, 0) => log::LevelFilter::Info,
+        (false, 1) => log::LevelFilter::Debug,
+        (false, _) => log::LevelFilter::Trace,
+    };
+    if log::set_logger(Box::leak(Box::new(Logger { level }))).is_ok() {
+        log::set_max_level(level);
     }
 }
 
 fn main() {
+    let metrics: Vec<_> = MetricKind::ALL.iter().map(|metric| metric.name()).collect();
+
     let matches = App::new("pio")
         .about("Perceptual Image Optimizer")
         .version(clap::crate_version!())
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .help("Writes only errors to standard error")
+                .global(true)
+                .conflicts_with("verbose"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .help("Writes more details to standard error, repeat for even more")
+                .multiple(true)
+                .global(true),
+        )
         .arg(
             Arg::with_name("INPUT")
-                .help("Input file to use, standard input is used when value is - or not set")
+                .help("Input files, directories or glob patterns to use, standard input is used when value is - or not set")
+                .multiple(true)
                 .index(1),
         )
         .arg(
@@ -421,6 +812,14 @@ fn main() {
                 .help("Sets output file")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("output-dir")
+                .long("output-dir")
+                .value_name("dir")
+                .help("Sets output directory mirroring the directory tree of the inputs")
+                .takes_value(true)
+                .conflicts_with_all(&["output", "in-place"]),
+        )
         .arg(
             Arg::with_name("output-format")
                 .long("output-format")
@@ -433,8 +832,101 @@ fn main() {
                     "webp",
                     #[cfg(feature = "avif")]
                     "avif",
+                    "auto",
+                ]),
+        )
+        .arg(
+            Arg::with_name("formats")
+                .long("formats")
+                .value_name("formats")
+                .help("Sets formats to try when output format is auto, or formats to write with --widths")
+                .takes_value(true)
+                .use_delimiter(true)
+                .possible_values(&[
+                    "jpeg",
+                    "png",
+                    "webp",
+                    #[cfg(feature = "avif")]
+                    "avif",
+                ]),
+        )
+        .arg(
+            Arg::with_name("widths")
+                .long("widths")
+                .value_name("widths")
+                .help("Writes each input at these widths in each of --formats to --output-dir with a manifest")
+                .takes_value(true)
+                .use_delimiter(true)
+                .validator(validate_pixels)
+                .requires("output-dir")
+                .conflicts_with_all(&[
+                    "output-format",
+                    "resize",
+                    "max-width",
+                    "max-height",
+                    "cache",
+                    "journal",
+                    "dry-run",
+                    "report",
                 ]),
         )
+        .arg(
+            Arg::with_name("picture")
+                .long("picture")
+                .help("Writes <picture> snippet and manifest of the variants of each input next to them")
+                .requires("widths"),
+        )
+        .arg(
+            Arg::with_name("sizes")
+                .long("sizes")
+                .value_name("sizes")
+                .help("Sets sizes attribute of the <picture> snippet")
+                .takes_value(true)
+                .default_value("100vw"),
+        )
+        .arg(
+            Arg::with_name("cache")
+                .long("cache")
+                .value_name("dir")
+                .help("Reuses outputs cached in the directory and caches new outputs")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cache-size")
+                .long("cache-size")
+                .value_name("bytes")
+                .help("Prunes least recently used outputs from the cache after the run")
+                .takes_value(true)
+                .requires("cache")
+                .validator(validate_size),
+        )
+        .arg(
+            Arg::with_name("journal")
+                .long("journal")
+                .value_name("file")
+                .help("Records inputs completed by a batch in a journal file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .help("Skips inputs completed according to the journal and removes temporary files of an interrupted run")
+                .requires("journal"),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Optimizes without writing anything and reports the projected savings")
+                .conflicts_with("journal"),
+        )
+        .arg(
+            Arg::with_name("report")
+                .long("report")
+                .value_name("format")
+                .help("Writes a report of the optimization to standard output, one line per file in a batch")
+                .takes_value(true)
+                .possible_values(&["json"]),
+        )
         .arg(
             Arg::with_name("in-place")
                 .long("in-place")
@@ -476,6 +968,32 @@ fn main() {
                 .takes_value(true)
                 .validator(validate_spread),
         )
+        .arg(
+            Arg::with_name("max-size")
+                .long("max-size")
+                .value_name("bytes")
+                .help("Searches the highest quality whose output fits in the given size")
+                .takes_value(true)
+                .validator(validate_size),
+        )
+        .arg(
+            Arg::with_name("max-bpp")
+                .long("max-bpp")
+                .value_name("bits")
+                .help("Like `--max-size` but the size is given in bits per pixel")
+                .takes_value(true)
+                .validator(validate_bpp),
+        )
+        .group(ArgGroup::with_name("size-budget").args(&["max-size", "max-bpp"]))
+        .arg(
+            Arg::with_name("floor")
+                .long("floor")
+                .value_name("quality")
+                .help("Sets the lowest acceptable quality when targeting file size")
+                .takes_value(true)
+                .requires("size-budget")
+                .validator(validate_quality),
+        )
         .arg(
             Arg::with_name("background-color")
                 .long("background-color")
@@ -492,6 +1010,47 @@ fn main() {
                 .long("no-transparency")
                 .help("Adds background color even if output format supports transparency"),
         )
+        .arg(
+            Arg::with_name("resize")
+                .long("resize")
+                .value_name("dimensions")
+                .help("Resizes image to WIDTHxHEIGHT, WIDTHx or xHEIGHT pixels before optimization")
+                .takes_value(true)
+                .validator(|x| parse_dimensions(&x).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("fit")
+                .long("fit")
+                .value_name("fit")
+                .help("Sets how image is fitted to both width and height of --resize: scaled inside them or scaled to cover them and cropped")
+                .takes_value(true)
+                .default_value("contain")
+                .possible_values(&["contain", "cover"]),
+        )
+        .arg(
+            Arg::with_name("max-width")
+                .long("max-width")
+                .value_name("pixels")
+                .help("Scales image down to at most this width keeping its aspect ratio")
+                .takes_value(true)
+                .validator(validate_pixels),
+        )
+        .arg(
+            Arg::with_name("max-height")
+                .long("max-height")
+                .value_name("pixels")
+                .help("Scales image down to at most this height keeping its aspect ratio")
+                .takes_value(true)
+                .validator(validate_pixels),
+        )
+        .arg(
+            Arg::with_name("sharpen")
+                .long("sharpen")
+                .value_name("amount")
+                .help("Sharpens resized image by amount, for example 0.5")
+                .takes_value(true)
+                .validator(validate_sharpen),
+        )
         .arg(
             Arg::with_name("optimization-failed")
                 .long("optimization-failed")
@@ -510,19 +1069,268 @@ fn main() {
                 .default_value("auto")
                 .possible_values(&["444", "422", "420", "auto"]),
         )
-        .get_matches();
-
-    pio(matches).unwrap_or_else(|err| {
-        eprintln!("{}", err);
-        std::process::exit(1);
-    })
-}
-
-#[cfg(test)]
-mod tests {
+        .arg(
+            Arg::with_name("quality-table")
+                .long("quality-table")
+                .value_name("file")
+                .help(
+                    "Uses quality table generated by `pio calibrate` instead of the builtin table",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metric")
+                .long("metric")
+                .value_name("metric")
+                .help("Sets metric used to compare images")
+                .takes_value(true)
+                .default_value("dssim")
+                .possible_values(&metrics),
+        )
+        .arg(
+            Arg::with_name("target")
+                .long("target")
+                .value_name("score")
+                .help("Targets metric score directly instead of converting quality to a score")
+                .takes_value(true)
+                .conflicts_with("max-size")
+                .conflicts_with("max-bpp")
+                .validator(validate_score),
+        )
+        .arg(
+            Arg::with_name("max-region")
+                .long("max-region")
+                .value_name("score")
+                .help("Sets the worst acceptable score of any region of the image")
+                .takes_value(true)
+                .conflicts_with("max-size")
+                .conflicts_with("max-bpp")
+                .validator(validate_score),
+        )
+        .arg(
+            Arg::with_name("tile-size")
+                .long("tile-size")
+                .value_name("pixels")
+                .help("Sets size of the regions used by `--max-region`")
+                .takes_value(true)
+                .default_value("16")
+                .validator(validate_tile_size),
+        )
+        .arg(
+            Arg::with_name("no-fast-search")
+                .long("no-fast-search")
+                .help("Uses only the slow encoder of the output format during the search"),
+        )
+        .arg(
+            Arg::with_name("max-encodes")
+                .long("max-encodes")
+                .value_name("count")
+                .help("Sets maximum number of encodes of the search per chroma subsampling")
+                .takes_value(true)
+                .default_value("7")
+                .validator(validate_encodes),
+        )
+        .arg(
+            Arg::with_name("jobs")
+                .long("jobs")
+                .short("j")
+                .value_name("count")
+                .help("Sets number of threads shared by all images, defaults to the number of CPUs")
+                .takes_value(true)
+                .validator(validate_jobs),
+        )
+        .arg(
+            Arg::with_name("tolerance")
+                .long("tolerance")
+                .value_name("percent")
+                .help("Sets how far from the target the smallest candidate may be")
+                .takes_value(true)
+                .default_value("5")
+                .validator(validate_tolerance),
+        )
+        .arg(
+            Arg::with_name("strict")
+                .long("strict")
+                .help("Never chooses output that misses the target")
+                .conflicts_with_all(&["max-size", "max-bpp"]),
+        )
+        .arg(
+            Arg::with_name("fallback")
+                .long("fallback")
+                .value_name("output")
+                .help("Sets output used in strict mode when the target can't be met")
+                .takes_value(true)
+                .possible_values(&["lossless", "original"])
+                .default_value("lossless"),
+        )
+        .subcommand(
+            SubCommand::with_name("calibrate")
+                .about("Generates quality table from a corpus of images")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Input files or directories")
+                        .required(true)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .help("Sets output file")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .help("Sets format to calibrate")
+                        .value_name("format")
+                        .takes_value(true)
+                        .default_value("jpeg")
+                        .possible_values(&[
+                            "jpeg",
+                            "png",
+                            "webp",
+                            #[cfg(feature = "avif")]
+                            "avif",
+                        ]),
+                )
+                .arg(
+                    Arg::with_name("chroma-subsampling")
+                        .long("chroma-subsampling")
+                        .value_name("xxx")
+                        .help("Specifies chroma subsampling")
+                        .takes_value(true)
+                        .default_value("420")
+                        .possible_values(&["444", "422", "420"]),
+                )
+                .arg(
+                    Arg::with_name("metric")
+                        .long("metric")
+                        .value_name("metric")
+                        .help("Sets metric used to compare images")
+                        .takes_value(true)
+                        .default_value("dssim")
+                        .possible_values(&metrics),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Optimizes images posted to a local HTTP service")
+                .arg(
+                    Arg::with_name("listen")
+                        .long("listen")
+                        .value_name("address")
+                        .help("Sets address and port to listen on")
+                        .takes_value(true)
+                        .default_value("127.0.0.1:8080"),
+                )
+                .arg(
+                    Arg::with_name("max-concurrency")
+                        .long("max-concurrency")
+                        .value_name("count")
+                        .help("Sets number of images optimized concurrently, other requests are refused, defaults to the number of CPUs")
+                        .takes_value(true)
+                        .validator(validate_concurrency),
+                )
+                .arg(
+                    Arg::with_name("max-connections")
+                        .long("max-connections")
+                        .value_name("count")
+                        .help("Sets number of connections answered concurrently, others wait until one is closed")
+                        .takes_value(true)
+                        .default_value("64")
+                        .validator(validate_connections),
+                )
+                .arg(
+                    Arg::with_name("max-body")
+                        .long("max-body")
+                        .value_name("bytes")
+                        .help("Sets size of the largest accepted image")
+                        .takes_value(true)
+                        .default_value("67108864")
+                        .validator(validate_size),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .long("jobs")
+                        .short("j")
+                        .value_name("count")
+                        .help("Sets number of threads shared by all images, defaults to the number of CPUs")
+                        .takes_value(true)
+                        .validator(validate_jobs),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("worker")
+                .about("Optimizes images given as JSON jobs, one per line of standard input")
+                .arg(
+                    Arg::with_name("max-concurrency")
+                        .long("max-concurrency")
+                        .value_name("count")
+                        .help("Sets number of jobs run concurrently, defaults to the number of CPUs")
+                        .takes_value(true)
+                        .validator(validate_concurrency),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .long("jobs")
+                        .short("j")
+                        .value_name("count")
+                        .help("Sets number of threads shared by all jobs, defaults to the number of CPUs")
+                        .takes_value(true)
+                        .validator(validate_jobs),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cache")
+                .about("Maintains the cache of optimized outputs")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("prune")
+                        .about("Removes least recently used outputs from the cache")
+                        .arg(
+                            Arg::with_name("DIR")
+                                .help("Cache directory")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("max-size")
+                                .long("max-size")
+                                .value_name("bytes")
+                                .help("Sets size the cache is pruned to")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(validate_size),
+                        ),
+                ),
+        )
+        .get_matches();
+
+    init_logger(&matches);
+
+    match matches.subcommand() {
+        ("calibrate", Some(matches)) => calibrate(matches),
+        ("cache", Some(matches)) => cache(matches),
+        ("serve", Some(matches)) => serve(matches),
+        ("worker", Some(matches)) => worker(matches),
+        _ => pio(matches),
+    }
+    .unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    })
+}
+
+#[cfg(test)]
+mod tests {
     use std::path::Path;
 
     use assert_cmd::Command;
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
+    use pio::common::Format;
+    use pio::optimize::decode;
+    use serde_json::{json, Value};
     use tempfile::tempdir;
 
     fn convert_image(input: impl AsRef<Path>, output: impl AsRef<Path>) {
@@ -748,4 +1556,829 @@ mod tests {
         assert_jpeg_sampling_factors(output, "1x1,1x1,1x1");
         Ok(())
     }
+
+    #[test]
+    fn fits_output_in_size_budget() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.jpeg");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .arg("--max-size")
+            .arg("3000")
+            .assert()
+            .success();
+        assert!(std::fs::metadata(&output)?.len() <= 3000);
+        Ok(())
+    }
+
+    #[test]
+    fn fails_if_size_budget_cannot_be_met() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.jpeg");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .arg("--max-size")
+            .arg("100")
+            .assert()
+            .failure();
+        assert!(std::fs::read(&output).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn requires_size_budget_for_floor() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.jpeg");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .arg("--floor")
+            .arg("50")
+            .assert()
+            .failure();
+        assert!(std::fs::read(&output).is_err());
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .arg("--floor")
+            .arg("50")
+            .arg("--max-bpp")
+            .arg("1")
+            .assert()
+            .success();
+        Ok(())
+    }
+
+    #[test]
+    fn selects_output_format_automatically() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(dir.path().join("output"))
+            .arg("--output-format")
+            .arg("auto")
+            .arg("--formats")
+            .arg("jpeg,webp")
+            .assert()
+            .success();
+        let outputs = std::fs::read_dir(dir.path())?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert!(outputs == ["output.jpeg"] || outputs == ["output.webp"]);
+        Ok(())
+    }
+
+    #[test]
+    fn targets_ssimulacra2_score() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.jpeg");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .arg("--metric")
+            .arg("ssimulacra2")
+            .arg("--target")
+            .arg("85")
+            .arg("--min")
+            .arg("0")
+            .arg("--max")
+            .arg("100")
+            .assert()
+            .success();
+        let original = decode(Format::PNG, &std::fs::read("images/image1-original.png")?)?;
+        let compressed = decode(Format::JPEG, &std::fs::read(&output)?)?;
+        let score = pio::ssimulacra2::Calculator::new(&original)
+            .unwrap()
+            .compare(&compressed)
+            .unwrap();
+        assert!((score - 85.0).abs() < 2.0);
+        Ok(())
+    }
+
+    #[test]
+    fn raises_quality_for_worst_region() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let global = dir.path().join("global.jpeg");
+        let region = dir.path().join("region.jpeg");
+        Command::cargo_bin("pio")?
+            .arg("images/image2-original.png")
+            .arg("-o")
+            .arg(&global)
+            .assert()
+            .success();
+        Command::cargo_bin("pio")?
+            .arg("images/image2-original.png")
+            .arg("-o")
+            .arg(&region)
+            .arg("--max-region")
+            .arg("0.03")
+            .assert()
+            .success();
+        assert!(std::fs::metadata(&region)?.len() > std::fs::metadata(&global)?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn fast_search_matches_full_search() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let fast = dir.path().join("fast.jpeg");
+        let full = dir.path().join("full.jpeg");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&fast)
+            .assert()
+            .success();
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&full)
+            .arg("--no-fast-search")
+            .assert()
+            .success();
+        assert_eq!(std::fs::read(&fast)?, std::fs::read(&full)?);
+        Ok(())
+    }
+
+    #[test]
+    fn limits_number_of_encodes() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(dir.path().join("output.jpeg"))
+            .arg("--chroma-subsampling")
+            .arg("420")
+            .arg("--no-fast-search")
+            .arg("--max-encodes")
+            .arg("2")
+            .output()?;
+        assert!(output.status.success());
+        let stderr = String::from_utf8(output.stderr)?;
+        assert!(stderr.matches(" quality ").count() <= 2);
+        Ok(())
+    }
+
+    #[test]
+    fn output_is_independent_of_jobs() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let mut outputs = Vec::new();
+        for jobs in &["1", "4"] {
+            let path = dir.path().join(format!("output-{}.jpeg", jobs));
+            let output = Command::cargo_bin("pio")?
+                .arg("images/image1-original.png")
+                .arg("-o")
+                .arg(&path)
+                .arg("--jobs")
+                .arg(jobs)
+                .output()?;
+            assert!(output.status.success());
+            outputs.push((output.stderr, std::fs::read(&path)?));
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn picks_smallest_candidate_meeting_target() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let path = dir.path().join("output.jpeg");
+        let output = Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&path)
+            .output()?;
+        assert!(output.status.success());
+        let size = std::fs::metadata(&path)?.len();
+        let stderr = String::from_utf8(output.stderr)?;
+        let rejected = stderr
+            .lines()
+            .filter(|line| line.starts_with("rejected ") && !line.contains("misses target"))
+            .map(|line| {
+                let bytes = line.split(": ").nth(1).unwrap().split(' ').next().unwrap();
+                bytes.parse::<u64>().unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert!(!rejected.is_empty());
+        assert!(rejected.iter().all(|&bytes| bytes >= size));
+        Ok(())
+    }
+
+    #[test]
+    fn strict_mode_keeps_original() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = "images/image1-original.png";
+        let output = dir.path().join("output.png");
+        let result = Command::cargo_bin("pio")?
+            .arg(input)
+            .arg("-o")
+            .arg(&output)
+            .arg("--strict")
+            .arg("--quality")
+            .arg("95")
+            .arg("--min")
+            .arg("70")
+            .arg("--max")
+            .arg("80")
+            .output()?;
+        assert!(result.status.success());
+        assert!(String::from_utf8(result.stderr)?.contains("keeping the original image"));
+        assert_eq!(std::fs::read(input)?, std::fs::read(&output)?);
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_input_only_in_its_format() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.jpeg");
+        let result = Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&output)
+            .arg("--strict")
+            .arg("--fallback")
+            .arg("original")
+            .arg("--quality")
+            .arg("100")
+            .arg("--min")
+            .arg("70")
+            .arg("--max")
+            .arg("80")
+            .output()?;
+        assert!(!result.status.success());
+        assert!(
+            String::from_utf8(result.stderr)?.contains("input can't be kept because it isn't jpeg")
+        );
+        assert!(!output.exists());
+
+        let result = Command::cargo_bin("pio")?
+            .arg("images/image-subsampling-test.png")
+            .arg("-o")
+            .arg(&output)
+            .arg("--quality")
+            .arg("100")
+            .arg("--spread")
+            .arg("0")
+            .arg("--optimization-failed")
+            .arg("copy")
+            .output()?;
+        assert!(!result.status.success());
+        assert!(String::from_utf8(result.stderr)?
+            .contains("input can't be copied because it isn't jpeg"));
+        assert!(!output.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn copies_input_when_optimization_fails() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input.jpeg");
+        let output = dir.path().join("output.jpeg");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&input)
+            .arg("--quality")
+            .arg("40")
+            .assert()
+            .success();
+        Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .arg("--quality")
+            .arg("100")
+            .arg("--spread")
+            .arg("0")
+            .arg("--optimization-failed")
+            .arg("copy")
+            .assert()
+            .success();
+        assert_eq!(std::fs::read(&input)?, std::fs::read(&output)?);
+        Ok(())
+    }
+
+    #[test]
+    fn writes_larger_output_only_without_copy() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input.jpeg");
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&input)
+            .arg("--quality")
+            .arg("40")
+            .assert()
+            .success();
+        let input_size = std::fs::metadata(&input)?.len();
+
+        let mut outputs = Vec::new();
+        for strategy in &["none", "copy"] {
+            let output = dir.path().join(format!("output-{}.jpeg", strategy));
+            let result = Command::cargo_bin("pio")?
+                .arg(&input)
+                .arg("-o")
+                .arg(&output)
+                .arg("--quality")
+                .arg("100")
+                .arg("--spread")
+                .arg("0")
+                .arg("--optimization-failed")
+                .arg(strategy)
+                .output()?;
+            assert!(result.status.success());
+            outputs.push((String::from_utf8(result.stderr)?, std::fs::read(&output)?));
+        }
+        assert!(outputs[0].0.contains("Output is larger than input"));
+        assert!(outputs[0].1.len() as u64 > input_size);
+        assert!(outputs[1].0.contains("copying input to output"));
+        assert_eq!(outputs[1].1, std::fs::read(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn optimizes_directory_tree() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input");
+        let output = dir.path().join("output");
+        std::fs::create_dir_all(input.join("nested"))?;
+        std::fs::copy("images/image1-original.png", input.join("a.png"))?;
+        std::fs::copy("images/image2-original.png", input.join("nested/b.png"))?;
+        std::fs::write(input.join("notes.txt"), "not an image")?;
+        let result = Command::cargo_bin("pio")?
+            .arg(&input)
+            .arg("--output-dir")
+            .arg(&output)
+            .arg("--output-format")
+            .arg("jpeg")
+            .output()?;
+        assert!(result.status.success());
+        assert!(String::from_utf8(result.stderr)?
+            .contains("2 optimized, 0 resumed, 1 skipped, 0 failed"));
+        assert!(output.join("a.jpeg").is_file());
+        assert!(output.join("nested/b.jpeg").is_file());
+        assert!(!output.join("notes.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn batch_output_is_independent_of_jobs() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let mut outputs = Vec::new();
+        for jobs in &["1", "3"] {
+            let output = dir.path().join(jobs);
+            Command::cargo_bin("pio")?
+                .arg("images/image1-original.png")
+                .arg("images/image2-original.png")
+                .arg("images/image3-original.png")
+                .arg("--output-dir")
+                .arg(&output)
+                .arg("--jobs")
+                .arg(jobs)
+                .assert()
+                .success();
+            outputs.push(vec![
+                std::fs::read(output.join("image1-original.png"))?,
+                std::fs::read(output.join("image2-original.png"))?,
+                std::fs::read(output.join("image3-original.png"))?,
+            ]);
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn prefixes_batch_lines_with_input() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let inputs = ["images/image1-original.png", "images/image2-original.png"];
+        let output = Command::cargo_bin("pio")?
+            .args(inputs)
+            .arg("--output-dir")
+            .arg(dir.path())
+            .arg("--jobs")
+            .arg("2")
+            .output()?;
+        assert!(output.status.success());
+        let stderr = String::from_utf8(output.stderr)?;
+        let lines = stderr.lines().collect::<Vec<_>>();
+        let (summary, lines) = lines.split_last().unwrap();
+        assert!(summary.starts_with("2 optimized"));
+        for input in &inputs {
+            let prefix = format!("{}: ", input);
+            assert!(lines
+                .iter()
+                .any(|line| line.starts_with(&prefix) && line.contains("trying png")));
+        }
+        assert!(lines.iter().all(|line| inputs
+            .iter()
+            .any(|input| line.starts_with(&format!("{}: ", input)))));
+        Ok(())
+    }
+
+    #[test]
+    fn resizes_image() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let resize = |args: &[&str]| -> Result<(usize, usize), Box<dyn std::error::Error>> {
+            let output = dir.path().join("output.png");
+            Command::cargo_bin("pio")?
+                .arg("images/image1-original.png")
+                .arg("-o")
+                .arg(&output)
+                .args(args)
+                .assert()
+                .success();
+            let image = decode(Format::PNG, &std::fs::read(&output)?)?;
+            Ok((image.width, image.height))
+        };
+        assert_eq!(resize(&["--resize", "100x100"])?, (100, 67));
+        assert_eq!(
+            resize(&["--resize", "100x100", "--fit", "cover"])?,
+            (100, 100)
+        );
+        assert_eq!(resize(&["--resize", "x50", "--sharpen", "0.5"])?, (75, 50));
+        assert_eq!(resize(&["--max-width", "400"])?, (200, 133));
+        assert_eq!(
+            resize(&["--max-width", "100", "--max-height", "40"])?,
+            (60, 40)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn writes_srcset() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .args(["--widths", "100,200,400", "--formats", "webp,jpeg"])
+            .arg("--output-dir")
+            .arg(dir.path())
+            .assert()
+            .success();
+        for (name, format, width) in [
+            ("image1-original-100w.webp", Format::WEBP, 100),
+            ("image1-original-100w.jpeg", Format::JPEG, 100),
+            ("image1-original-200w.webp", Format::WEBP, 200),
+            ("image1-original-200w.jpeg", Format::JPEG, 200),
+        ] {
+            let image = decode(format, &std::fs::read(dir.path().join(name))?)?;
+            assert_eq!(image.width, width);
+        }
+        assert!(!dir.path().join("image1-original-400w.webp").exists());
+
+        let manifest: Value =
+            serde_json::from_str(&std::fs::read_to_string(dir.path().join("manifest.json"))?)?;
+        let source = match manifest.get("sources") {
+            Some(Value::Array(sources)) => &sources[0],
+            _ => panic!("manifest has no sources"),
+        };
+        match source.get("variants") {
+            Some(Value::Array(variants)) => assert_eq!(variants.len(), 4),
+            _ => panic!("manifest has no variants"),
+        }
+        assert_eq!(source.get("skipped_widths"), Some(&json!([400])));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_srcset_inputs_with_same_stem() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        for name in ["x", "y"] {
+            std::fs::create_dir(dir.path().join(name))?;
+            std::fs::copy(
+                "images/image1-original.png",
+                dir.path().join(name).join("a.png"),
+            )?;
+        }
+        let output = dir.path().join("output");
+        let result = Command::cargo_bin("pio")?
+            .arg(dir.path().join("x").join("a.png"))
+            .arg(dir.path().join("y").join("a.png"))
+            .args(["--widths", "100", "--picture"])
+            .arg("--output-dir")
+            .arg(&output)
+            .output()?;
+        assert!(!result.status.success());
+        let stderr = String::from_utf8(result.stderr)?;
+        assert!(stderr.contains("rename one of the inputs"));
+
+        let manifest: Value =
+            serde_json::from_str(&std::fs::read_to_string(output.join("a.manifest.json"))?)?;
+        assert_eq!(
+            manifest.get("input").and_then(Value::as_str),
+            Some(dir.path().join("x").join("a.png").to_str().unwrap())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn writes_picture_snippet() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .args(["--widths", "100,200", "--formats", "webp,jpeg", "--picture"])
+            .arg("--output-dir")
+            .arg(dir.path())
+            .assert()
+            .success();
+        let html = std::fs::read_to_string(dir.path().join("image1-original.html"))?;
+        assert!(html.contains(
+            "<source type=\"image/webp\" srcset=\"image1-original-100w.webp 100w, image1-original-200w.webp 200w\""
+        ));
+        assert!(html.contains("<img src=\"image1-original-200w.jpeg\""));
+
+        let manifest: Value = serde_json::from_str(&std::fs::read_to_string(
+            dir.path().join("image1-original.manifest.json"),
+        )?)?;
+        let variants = match manifest.get("variants") {
+            Some(Value::Array(variants)) => variants,
+            _ => panic!("manifest has no variants"),
+        };
+        let mimes: Vec<_> = variants
+            .iter()
+            .filter_map(|variant| variant.get("mime").and_then(Value::as_str))
+            .collect();
+        assert_eq!(
+            mimes,
+            ["image/webp", "image/webp", "image/jpeg", "image/jpeg"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn dry_run_writes_nothing() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output");
+        let result = Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("images/image2-original.png")
+            .arg("--output-dir")
+            .arg(&output)
+            .arg("--output-format")
+            .arg("jpeg")
+            .arg("--dry-run")
+            .output()?;
+        assert!(result.status.success());
+        assert!(!output.exists());
+        let stderr = String::from_utf8(result.stderr)?;
+        assert!(stderr.contains("image1-original.png: 21411 -> "));
+        assert!(stderr.contains("chosen quality "));
+        assert!(stderr.contains("2 optimized, 0 resumed, 0 skipped, 0 failed, would save "));
+        Ok(())
+    }
+
+    #[test]
+    fn resumes_batch_from_journal() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input");
+        let journal = dir.path().join("journal.tsv");
+        std::fs::create_dir(&input)?;
+        std::fs::copy("images/image1-original.png", input.join("a.png"))?;
+        let run = |resume: bool| -> Result<String, Box<dyn std::error::Error>> {
+            let mut command = Command::cargo_bin("pio")?;
+            command
+                .arg(&input)
+                .arg("--in-place")
+                .arg("--journal")
+                .arg(&journal);
+            if resume {
+                command.arg("--resume");
+            }
+            let output = command.output()?;
+            assert!(output.status.success());
+            Ok(String::from_utf8(output.stderr)?)
+        };
+        run(false)?;
+        let optimized = std::fs::read(input.join("a.png"))?;
+
+        // Simulate a run that was killed while overwriting a new input.
+        std::fs::copy("images/image2-original.png", input.join("b.png"))?;
+        std::fs::write(input.join(".pio-0123456789abcdef.tmp"), "partial")?;
+        let stderr = run(true)?;
+        assert!(stderr.contains("a.png: already optimized"));
+        assert!(stderr.contains("1 optimized, 1 resumed"));
+        assert!(!input.join(".pio-0123456789abcdef.tmp").exists());
+        assert_eq!(optimized, std::fs::read(input.join("a.png"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn reuses_cached_output() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let cache = dir.path().join("cache");
+        let mut outputs = Vec::new();
+        for name in &["first.jpeg", "second.jpeg"] {
+            let path = dir.path().join(name);
+            let output = Command::cargo_bin("pio")?
+                .arg("images/image1-original.png")
+                .arg("-o")
+                .arg(&path)
+                .arg("--cache")
+                .arg(&cache)
+                .output()?;
+            assert!(output.status.success());
+            outputs.push((String::from_utf8(output.stderr)?, std::fs::read(&path)?));
+        }
+        assert!(!outputs[0].0.contains("using cached output"));
+        assert!(outputs[1].0.contains("using cached output"));
+        assert_eq!(outputs[0].1, outputs[1].1);
+
+        Command::cargo_bin("pio")?
+            .arg("cache")
+            .arg("prune")
+            .arg(&cache)
+            .arg("--max-size")
+            .arg("1")
+            .assert()
+            .success();
+        assert_eq!(std::fs::read_dir(&cache)?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn writes_json_report() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.jpeg");
+        let result = Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("--output")
+            .arg(&output)
+            .arg("--report")
+            .arg("json")
+            .output()?;
+        assert!(result.status.success());
+        let report: Value = serde_json::from_str(&String::from_utf8(result.stdout)?)?;
+        assert_eq!(report.get("input_format"), Some(&Value::from("png")));
+        assert_eq!(report.get("output_format"), Some(&Value::from("jpeg")));
+        assert_eq!(
+            report.get("output_size").and_then(Value::as_f64),
+            Some(std::fs::metadata(&output)?.len() as f64)
+        );
+        let attempts = match report.get("attempts") {
+            Some(Value::Array(attempts)) => attempts,
+            _ => panic!("missing attempts"),
+        };
+        assert!(attempts
+            .iter()
+            .any(|attempt| attempt.get("quality") == report.get("quality")));
+        assert!(report
+            .get("timings_ms")
+            .and_then(|t| t.get("search"))
+            .is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn quiet_mode_writes_only_errors() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let run = |flag: &str| -> Result<String, Box<dyn std::error::Error>> {
+            let output = Command::cargo_bin("pio")?
+                .arg("images/image1-original.png")
+                .arg("-o")
+                .arg(dir.path().join("output.jpeg"))
+                .arg(flag)
+                .output()?;
+            assert!(output.status.success());
+            Ok(String::from_utf8(output.stderr)?)
+        };
+        assert_eq!(run("--quiet")?, "");
+        let verbose = run("-v")?;
+        // Standard error isn't a terminal, so attempts are written without the search bar.
+        assert!(verbose.contains("jpeg 4:2:0 "));
+        assert!(!verbose.contains('|'));
+        assert!(verbose.contains("searched in"));
+        Ok(())
+    }
+
+    #[test]
+    fn serves_optimized_images() -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::net::TcpStream;
+        use std::process::Stdio;
+
+        let mut server = std::process::Command::new(assert_cmd::cargo::cargo_bin("pio"))
+            .args(["serve", "--listen", "127.0.0.1:0"])
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let mut stderr = BufReader::new(server.stderr.take().unwrap());
+        let mut line = String::new();
+        stderr.read_line(&mut line)?;
+        let address = line.trim().trim_start_matches("listening on http://");
+        let request = |head: String, body: &[u8]| -> std::io::Result<(String, Vec<u8>)> {
+            let mut stream = TcpStream::connect(address)?;
+            stream.write_all(head.as_bytes())?;
+            stream.write_all(body)?;
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response)?;
+            let end = response
+                .windows(4)
+                .position(|window| window == b"\r\n\r\n")
+                .unwrap_or(response.len());
+            let head = String::from_utf8_lossy(&response[..end]).into_owned();
+            Ok((head, response.get(end + 4..).unwrap_or(&[]).to_vec()))
+        };
+
+        let image = std::fs::read("images/image1-original.png")?;
+        let optimized = request(
+            format!(
+                "POST /?quality=80 HTTP/1.1\r\nAccept: image/webp\r\nContent-Length: {}\r\n\r\n",
+                image.len()
+            ),
+            &image,
+        );
+        let health = request("GET /health HTTP/1.1\r\n\r\n".to_string(), &[]);
+        server.kill()?;
+
+        let (head, body) = optimized?;
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        assert!(head.contains("Content-Type: image/webp"));
+        assert!(head.contains("X-Pio-Quality: "));
+        assert!(head.contains("X-Pio-Score: "));
+        assert_eq!(Format::from_magic(&body), Some(Format::WEBP));
+        let (head, body) = health?;
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        assert!(String::from_utf8(body)?.contains("\"status\":\"ok\""));
+        Ok(())
+    }
+
+    #[test]
+    fn runs_worker_jobs() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let output = dir.path().join("output.webp");
+        let image = std::fs::read("images/image1-original.png")?;
+        let jobs = format!(
+            "{}\n{}\n{{\"id\":12345678901234567890,\"input\":\"missing.png\"}}\n",
+            json!({
+                "id": 1,
+                "input": "images/image1-original.png",
+                "output": output,
+                "format": "webp",
+            }),
+            json!({
+                "id": "second",
+                "data": BASE64.encode(&image),
+                "format": "jpeg",
+                "quality": 70,
+            }),
+        );
+        let result = Command::cargo_bin("pio")?
+            .args(["worker", "--max-concurrency", "2"])
+            .write_stdin(jobs)
+            .output()?;
+        assert!(result.status.success());
+        let stdout = String::from_utf8(result.stdout)?;
+        assert!(stdout.contains("\"id\":12345678901234567890,"));
+        let results = stdout
+            .lines()
+            .map(serde_json::from_str::<Value>)
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(results.len(), 3);
+        let result = |id: Value| results.iter().find(|result| result.get("id") == Some(&id));
+
+        let first = result(1u8.into()).unwrap();
+        assert_eq!(first.get("status").and_then(Value::as_str), Some("ok"));
+        assert_eq!(
+            Format::from_magic(&std::fs::read(&output)?),
+            Some(Format::WEBP)
+        );
+        let second = result("second".into()).unwrap();
+        assert_eq!(second.get("format").and_then(Value::as_str), Some("jpeg"));
+        let data = second.get("data").and_then(Value::as_str).unwrap();
+        assert_eq!(
+            Format::from_magic(&BASE64.decode(data)?),
+            Some(Format::JPEG)
+        );
+        let third = result(12345678901234567890u64.into()).unwrap();
+        assert_eq!(third.get("status").and_then(Value::as_str), Some("error"));
+        Ok(())
+    }
+
+    #[test]
+    fn calibrates_quality_table() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let table = dir.path().join("table.csv");
+        Command::cargo_bin("pio")?
+            .arg("calibrate")
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(&table)
+            .assert()
+            .success();
+        Command::cargo_bin("pio")?
+            .arg("images/image1-original.png")
+            .arg("-o")
+            .arg(dir.path().join("output.jpeg"))
+            .arg("--quality-table")
+            .arg(&table)
+            .assert()
+            .success();
+        Ok(())
+    }
 }
@@ -0,0 +1,103 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+#[cfg(feature = "butteraugli")]
+use crate::butteraugli;
+use crate::common::Image;
+use crate::{ssim, ssimulacra2};
+
+/// Perceptual metric that compares compressed images to the original image.
+pub trait Metric: Send + Sync {
+    /// Score `compressed` image against the original image. How scores relate to similarity
+    /// depends on the metric, see `MetricKind::distance`.
+    fn compare(&self, compressed: &Image) -> Option<f64>;
+
+    /// Like `compare` but also score the worst `tile_size` by `tile_size` region of the image.
+    /// Metrics without local scores return `None` for the region.
+    fn compare_regions(&self, compressed: &Image, tile_size: usize) -> Option<(f64, Option<f64>)> {
+        let _ = tile_size;
+        Some((self.compare(compressed)?, None))
+    }
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum MetricKind {
+    Dssim,
+    Ssimulacra2,
+    #[cfg(feature = "butteraugli")]
+    Butteraugli,
+}
+
+impl MetricKind {
+    /// All metrics compiled into this build.
+    pub const ALL: &'static [Self] = &[
+        Self::Dssim,
+        Self::Ssimulacra2,
+        #[cfg(feature = "butteraugli")]
+        Self::Butteraugli,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "dssim" => Some(Self::Dssim),
+            "ssimulacra2" => Some(Self::Ssimulacra2),
+            #[cfg(feature = "butteraugli")]
+            "butteraugli" => Some(Self::Butteraugli),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Dssim => "dssim",
+            Self::Ssimulacra2 => "ssimulacra2",
+            #[cfg(feature = "butteraugli")]
+            Self::Butteraugli => "butteraugli",
+        }
+    }
+
+    /// Short name of the metric for progress output.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Dssim => "SSIM",
+            Self::Ssimulacra2 => "SSIMULACRA2",
+            #[cfg(feature = "butteraugli")]
+            Self::Butteraugli => "BA",
+        }
+    }
+
+    /// Score of identical images.
+    pub fn perfect(&self) -> f64 {
+        match self {
+            Self::Dssim => 0.0,
+            Self::Ssimulacra2 => 100.0,
+            #[cfg(feature = "butteraugli")]
+            Self::Butteraugli => 0.0,
+        }
+    }
+
+    /// Distance of `score` from the score of identical images. Unlike scores, distances of all
+    /// metrics grow as quality gets worse.
+    pub fn distance(&self, score: f64) -> f64 {
+        (score - self.perfect()).abs()
+    }
+
+    /// Prepare metric for comparing images to `original`.
+    pub fn prepare(&self, original: &Image) -> Option<Box<dyn Metric>> {
+        match self {
+            Self::Dssim => Some(Box::new(ssim::Calculator::new(original)?)),
+            Self::Ssimulacra2 => Some(Box::new(ssimulacra2::Calculator::new(original)?)),
+            #[cfg(feature = "butteraugli")]
+            Self::Butteraugli => Some(Box::new(butteraugli::Calculator::new(original)?)),
+        }
+    }
+
+    /// Like `prepare` but also keeps what `Metric::compare_regions` needs, which costs memory
+    /// for every comparison.
+    pub fn prepare_regions(&self, original: &Image) -> Option<Box<dyn Metric>> {
+        match self {
+            Self::Dssim => Some(Box::new(ssim::Calculator::with_regions(original)?)),
+            _ => self.prepare(original),
+        }
+    }
+}
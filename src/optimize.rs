@@ -0,0 +1,1231 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-FileCopyrightText: 2019-2020 Johannes Siipola
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+#[cfg(feature = "avif")]
+use crate::avif;
+use crate::common::{
+    ChromaSubsampling, ChromaSubsamplingOption, CompressResult, FastCompressResult, Format, Image,
+    ReadResult,
+};
+#[cfg(feature = "jxl")]
+use crate::jxl;
+use crate::metric::{Metric, MetricKind};
+use crate::progress::{Event, LogObserver, Observer};
+use crate::quality::QualityTable;
+use crate::{jpeg, png, webp};
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+pub type LossyCompressor =
+    Box<dyn Fn(&Image, u8, ChromaSubsampling) -> CompressResult + Send + Sync>;
+pub type LosslessCompressor = Box<dyn Fn(&Image) -> CompressResult + Send + Sync>;
+type FastCompressor =
+    Box<dyn Fn(&Image, u8, ChromaSubsampling) -> FastCompressResult + Send + Sync>;
+pub type AutoResult = Result<(OptimizeResult, Vec<(Format, Result<usize, String>)>), String>;
+
+/// Quality target of the search.
+#[derive(Copy, Clone)]
+pub enum Target {
+    /// JPEG-like quality between 0 and 100 that is converted to a score using a quality table.
+    Quality(u8),
+    /// Score of the metric used by the search.
+    Score(f64),
+}
+
+/// Output used in strict mode when no lossy candidate meets the target.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Fallback {
+    /// Lossless compression, or the original image if the format doesn't support it.
+    Lossless,
+    /// The original image.
+    Original,
+}
+
+/// Parameters of the quality search.
+#[derive(Clone)]
+pub struct OptimizeOptions {
+    /// Target the search tries to get as close as possible to.
+    pub target: Target,
+    pub min_quality: u8,
+    pub max_quality: u8,
+    pub chroma_subsampling: ChromaSubsamplingOption,
+    pub format: Format,
+    /// Metric used to compare compressed images to the original.
+    pub metric: MetricKind,
+    /// Also try lossless compression if the output format supports it.
+    pub lossless: bool,
+    /// Size budget in bytes. When set, the highest quality whose output fits the budget is
+    /// searched instead of the quality closest to `target`.
+    pub max_size: Option<u64>,
+    /// Worst acceptable quality when searching with `max_size`.
+    pub floor: Option<Target>,
+    /// Table used to convert quality targets to scores instead of the builtin table of the output
+    /// format.
+    pub quality_table: Option<QualityTable>,
+    /// Ceiling of the score of the worst `tile_size` by `tile_size` region. The search raises
+    /// quality until both `target` and the ceiling are met. Ignored when searching with
+    /// `max_size`.
+    pub max_region: Option<f64>,
+    pub tile_size: usize,
+    /// Search with the fast encoder of the format first and use the full encoder only near the
+    /// result.
+    pub fast_search: bool,
+    /// Maximum number of encodes of the search per chroma subsampling. When the search uses the
+    /// fast encoder, the full encoder is used at most twice after it.
+    pub max_encodes: usize,
+    /// Number of threads used to search chroma subsamplings and lossless compression
+    /// concurrently. Zero uses one thread per CPU. Ignored when called in a rayon thread pool,
+    /// whose threads are used instead.
+    pub jobs: usize,
+    /// Fraction by which distance of a candidate from identical images may exceed distance of the
+    /// target for the candidate to still meet the target. The smallest candidate meeting the
+    /// target is chosen.
+    pub tolerance: f64,
+    /// Never choose a candidate that misses the target, not even within `tolerance`. If the
+    /// maximum quality misses the target, the given fallback is used instead. Ignored when
+    /// searching with `max_size`.
+    pub strict: Option<Fallback>,
+    /// Receives progress of the search.
+    pub observer: Arc<dyn Observer>,
+}
+
+impl OptimizeOptions {
+    /// Options matching the defaults of the command-line tool: quality 85 with spread of 10.
+    pub fn new(format: Format) -> Self {
+        Self {
+            target: Target::Quality(85),
+            min_quality: 75,
+            max_quality: 95,
+            chroma_subsampling: if format.supports_chroma_subsampling() {
+                ChromaSubsamplingOption::Auto
+            } else {
+                ChromaSubsamplingOption::None
+            },
+            format,
+            metric: MetricKind::Dssim,
+            lossless: true,
+            max_size: None,
+            floor: None,
+            quality_table: None,
+            max_region: None,
+            tile_size: 16,
+            fast_search: true,
+            max_encodes: 7,
+            jobs: 0,
+            tolerance: 0.05,
+            strict: None,
+            observer: Arc::new(LogObserver),
+        }
+    }
+
+    /// Description of the options that affect the output. For example, `jobs` isn't included.
+    /// The same image is compressed to the same output with options that have equal
+    /// fingerprints.
+    pub fn fingerprint(&self) -> String {
+        let target = |target: Target| match target {
+            Target::Quality(quality) => format!("quality {}", quality),
+            Target::Score(score) => format!("score {}", score),
+        };
+        format!(
+            "pio {}; target {}; min {}; max {}; chroma subsampling {}; format {}; metric {}; \
+             lossless {}; max size {:?}; floor {:?}; quality table {}; max region {:?}; \
+             tile size {}; fast search {}; max encodes {}; tolerance {}; strict {:?}",
+            env!("CARGO_PKG_VERSION"),
+            target(self.target),
+            self.min_quality,
+            self.max_quality,
+            match self.chroma_subsampling {
+                ChromaSubsamplingOption::None => "none".to_string(),
+                ChromaSubsamplingOption::Auto => "auto".to_string(),
+                ChromaSubsamplingOption::Manual(sampling) => sampling.to_string(),
+            },
+            self.format.ext(),
+            self.metric.name(),
+            self.lossless,
+            self.max_size,
+            self.floor.map(target),
+            match &self.quality_table {
+                Some(table) => format!("{:x}", Sha256::digest(table.to_string())),
+                None => "builtin".to_string(),
+            },
+            self.max_region,
+            self.tile_size,
+            self.fast_search,
+            self.max_encodes,
+            self.tolerance,
+            self.strict,
+        )
+    }
+
+    /// Ceiling of the worst region unless it is ignored for searching with `max_size`.
+    pub fn region_ceiling(&self) -> Option<f64> {
+        match self.max_size {
+            Some(_) => None,
+            None => self.max_region,
+        }
+    }
+
+    /// Whether `score` meets `target` within `tolerance`. Strict mode allows no tolerance.
+    fn within_tolerance(&self, score: f64, target: f64) -> bool {
+        let tolerance = match (self.max_size, self.strict) {
+            (None, Some(_)) => 0.0,
+            _ => self.tolerance,
+        };
+        self.metric.distance(score) <= self.metric.distance(target) * (1.0 + tolerance)
+    }
+
+    /// Table used to convert quality targets to scores for `format`.
+    pub fn table(&self, format: Format) -> Option<&QualityTable> {
+        match &self.quality_table {
+            Some(table) => Some(table),
+            None => QualityTable::builtin(format, self.metric),
+        }
+    }
+
+    /// Convert `target` to a score of `metric` for `format`.
+    pub fn score(&self, target: Target, format: Format) -> Result<f64, String> {
+        let quality = match target {
+            Target::Quality(quality) => quality,
+            Target::Score(score) => return Ok(score),
+        };
+        let table = self.table(format).ok_or_else(|| {
+            format!(
+                "no quality table for {} with {}, use a score target or a custom quality table",
+                format.ext(),
+                self.metric.name()
+            )
+        })?;
+        if table.metric() != self.metric {
+            return Err(format!(
+                "quality table is for {} but {} is used",
+                table.metric().name(),
+                self.metric.name()
+            ));
+        }
+        Ok(table.target(quality))
+    }
+}
+
+/// Outcome of the quality search.
+pub struct OptimizeResult {
+    pub format: Format,
+    /// Quality of the chosen lossy image or `None` if lossless compression was chosen.
+    pub quality: Option<u8>,
+    pub score: f64,
+    /// Chroma subsampling of the chosen image or `None` if the format doesn't use it.
+    pub chroma_subsampling: Option<ChromaSubsampling>,
+    pub lossless: bool,
+    pub buffer: Vec<u8>,
+    /// Other candidates compressed during the search.
+    pub rejected: Vec<Rejected>,
+    /// Whether the original image should be kept because the target couldn't be met in strict
+    /// mode. `buffer` is empty in that case.
+    pub original: bool,
+    /// Every compression tried during the search in the order of chroma subsamplings and
+    /// formats.
+    pub attempts: Vec<Attempt>,
+}
+
+/// Compression tried during the search.
+#[derive(Clone)]
+pub struct Attempt {
+    pub format: Format,
+    /// Quality of the lossy attempt or `None` for lossless compression.
+    pub quality: Option<u8>,
+    pub chroma_subsampling: Option<ChromaSubsampling>,
+    pub score: f64,
+    /// Score of the worst region if a region ceiling is used.
+    pub region: Option<f64>,
+    pub size: usize,
+    /// Whether the fast encoder was used during the coarse pass of the search.
+    pub fast: bool,
+}
+
+/// Attempts of one search. Each attempt is delivered to the observer as soon as it's made and
+/// kept for the result.
+struct Progress {
+    /// Size of the input file reported with the attempts.
+    original_size: u64,
+    attempts: Vec<Attempt>,
+}
+
+/// Candidate that was compressed during the search but not chosen.
+pub struct Rejected {
+    /// Quality of the lossy candidate or `None` for lossless compression.
+    pub quality: Option<u8>,
+    pub score: f64,
+    pub chroma_subsampling: Option<ChromaSubsampling>,
+    pub size: usize,
+    /// Whether the candidate met the target (or fit the size budget) but something else was
+    /// preferred.
+    pub met_target: bool,
+}
+
+impl Rejected {
+    /// Human-readable summary of the candidate.
+    pub fn describe(&self, metric: MetricKind) -> String {
+        let name = match (self.quality, self.chroma_subsampling) {
+            (Some(quality), Some(sampling)) => format!("quality {} {}", quality, sampling),
+            (Some(quality), None) => format!("quality {}", quality),
+            (None, _) => "lossless".to_string(),
+        };
+        format!(
+            "{}: {} bytes  {:.6} {}{}",
+            name,
+            self.size,
+            self.score,
+            metric.label(),
+            if self.met_target {
+                ""
+            } else {
+                "  (misses target)"
+            }
+        )
+    }
+}
+
+pub fn decode(format: Format, buffer: &[u8]) -> ReadResult {
+    match format {
+        Format::JPEG => jpeg::read(buffer),
+        Format::PNG => png::read(buffer),
+        Format::WEBP => webp::read(buffer),
+        #[cfg(feature = "avif")]
+        Format::AVIF => avif::read(buffer),
+        #[cfg(feature = "jxl")]
+        Format::JXL => jxl::read(buffer),
+    }
+}
+
+/// Lossy compressor using the fast encoder of `format`, if any. Compressed images are decoded to
+/// compare them to the original.
+pub fn fast_compressor(format: Format) -> Option<LossyCompressor> {
+    let compress: FastCompressor = match format {
+        Format::JPEG => Box::new(jpeg::compress_fast),
+        Format::PNG => Box::new(|img, q, _cs| png::compress_fast(img, q)),
+        Format::WEBP => Box::new(|img, q, _cs| webp::compress_fast(img, q, false)),
+        #[cfg(feature = "avif")]
+        Format::AVIF => Box::new(|img, q, _cs| avif::compress_fast(img, q)),
+        #[cfg(feature = "jxl")]
+        Format::JXL => return None,
+    };
+    Some(Box::new(move |img, q, cs| {
+        let buffer = compress(img, q, cs)?;
+        Ok((decode(format, &buffer)?, buffer))
+    }))
+}
+
+pub fn compressors(format: Format) -> (LossyCompressor, Option<LosslessCompressor>) {
+    match format {
+        Format::JPEG => (Box::new(jpeg::compress), None),
+        Format::PNG => (Box::new(|img, q, _cs| png::compress(img, q)), None),
+        Format::WEBP => (
+            Box::new(|img, q, _cs| webp::compress(img, q, false)),
+            Some(Box::new(|img| webp::compress(img, 100, true))),
+        ),
+        #[cfg(feature = "avif")]
+        Format::AVIF => (Box::new(|img, q, _cs| avif::compress(img, q)), None),
+        #[cfg(feature = "jxl")]
+        Format::JXL => (
+            Box::new(|img, q, _cs| jxl::compress(img, q, false)),
+            Some(Box::new(|img| jxl::compress(img, 100, true))),
+        ),
+    }
+}
+
+/// Lossy image found by the search.
+struct Candidate {
+    quality: u8,
+    score: f64,
+    /// Whether the worst region is within `max_region`. Always true without the ceiling.
+    region_ok: bool,
+    buffer: Vec<u8>,
+}
+
+impl Candidate {
+    /// Whether score of `self` is closer to `target` than score of `other`. Candidates meeting
+    /// the region ceiling are always preferred, and if neither meets it, the higher quality is.
+    fn closer(&self, other: &Candidate, target: f64) -> bool {
+        match (self.region_ok, other.region_ok) {
+            (true, true) => (self.score - target).abs() < (other.score - target).abs(),
+            (true, false) => true,
+            (false, true) => false,
+            (false, false) => self.quality > other.quality,
+        }
+    }
+}
+
+/// Quality measured during the search.
+#[derive(Copy, Clone)]
+struct Probe {
+    quality: i32,
+    error: f64,
+}
+
+/// Search state shared by the coarse and the fine pass.
+struct Search<'a> {
+    options: &'a OptimizeOptions,
+    target: f64,
+    best: Option<Candidate>,
+}
+
+impl<'a> Search<'a> {
+    fn new(options: &'a OptimizeOptions, target: f64) -> Self {
+        Self {
+            options,
+            target,
+            best: None,
+        }
+    }
+
+    /// Signed distance of `candidate` from the goal of the search. It grows with quality and is
+    /// negative or zero for candidates whose quality is too low.
+    fn error(&self, candidate: &Candidate) -> f64 {
+        let metric = self.options.metric;
+        match self.options.max_size {
+            Some(max_size) => candidate.buffer.len() as f64 - max_size as f64,
+            None => metric.distance(self.target) - metric.distance(candidate.score),
+        }
+    }
+
+    /// Search quality using at most `max_encodes` encodes. The first quality is `seed` or guessed
+    /// using `table` and following qualities are interpolated from the measurements. Bisection is
+    /// used as a fallback if interpolation converges slowly.
+    fn run(
+        &mut self,
+        table: Option<&QualityTable>,
+        seed: Option<u8>,
+        mut evaluate: impl FnMut(u8, (u8, u8)) -> Result<Candidate, String>,
+    ) -> Result<(), String> {
+        let options = self.options;
+        // The table is only useful for predicting scores, not sizes.
+        let table = match options.max_size {
+            Some(_) => None,
+            None => table,
+        };
+        // Closest measurement with too low quality and closest with high enough quality.
+        let mut lo: Option<Probe> = None;
+        let mut hi: Option<Probe> = None;
+        let mut previous: Option<Probe> = None;
+        // Range of qualities that haven't been ruled out yet.
+        let (mut low, mut high) = (options.min_quality as i32, options.max_quality as i32);
+        let mut quality = match (seed, table) {
+            (Some(seed), _) => seed as i32,
+            (None, Some(table)) => self.predict(table, low, high, 1.0),
+            (None, None) => (low + high) / 2,
+        };
+        for _ in 0..options.max_encodes {
+            let candidate = evaluate(quality as u8, (low as u8, high as u8))?;
+            let probe = Probe {
+                quality,
+                error: self.error(&candidate),
+            };
+            let width = high - low;
+            if self.consider(candidate) {
+                low = quality + 1;
+                lo = Some(probe);
+            } else {
+                high = quality - 1;
+                hi = Some(probe);
+            }
+            if low > high {
+                break;
+            }
+
+            quality = match (&lo, &hi) {
+                // Interpolate between measurements on both sides of the target unless the previous
+                // step didn't at least halve the range.
+                (Some(lo), Some(hi)) if lo.error < hi.error && 2 * (high - low) <= width => {
+                    let t = -lo.error / (hi.error - lo.error);
+                    lo.quality + (t * (hi.quality - lo.quality) as f64).round() as i32
+                }
+                (Some(_), Some(_)) => (low + high) / 2,
+                // Only one side is known. Assume that the score differs from the table by a
+                // constant factor.
+                _ => match (table, &previous) {
+                    (Some(table), _) => {
+                        let expected = options.metric.distance(table.target(probe.quality as u8));
+                        let measured = options.metric.distance(self.target) - probe.error;
+                        if expected > 0.0 {
+                            self.predict(table, low, high, measured / expected)
+                        } else {
+                            (low + high) / 2
+                        }
+                    }
+                    // Secant through the last two measurements.
+                    (None, Some(previous)) if previous.error != probe.error => {
+                        let slope = (probe.error - previous.error)
+                            / (probe.quality - previous.quality) as f64;
+                        probe.quality - (probe.error / slope).round() as i32
+                    }
+                    (None, _) => (low + high) / 2,
+                },
+            }
+            .max(low)
+            .min(high);
+            previous = Some(probe);
+        }
+        Ok(())
+    }
+
+    /// Check `seed` and the next quality towards the target from it, so at most two encodes.
+    /// Used with the full encoder after the fast encoder has found `seed`, as outputs of the two
+    /// encoders with the same quality are close.
+    fn refine(
+        &mut self,
+        seed: u8,
+        mut evaluate: impl FnMut(u8, (u8, u8)) -> Result<Candidate, String>,
+    ) -> Result<(), String> {
+        let (low, high) = (self.options.min_quality, self.options.max_quality);
+        let seed = seed.max(low).min(high);
+        let step = if self.consider(evaluate(seed, (low, high))?) {
+            Some(seed + 1)
+                .filter(|&quality| quality <= high)
+                .map(|quality| (quality, (quality, high)))
+        } else {
+            seed.checked_sub(1)
+                .filter(|&quality| quality >= low)
+                .map(|quality| (quality, (low, quality)))
+        };
+        if let Some((quality, bounds)) = step {
+            self.consider(evaluate(quality, bounds)?);
+        }
+        Ok(())
+    }
+
+    /// Quality in `low..=high` whose score in `table` multiplied by `factor` is closest to the
+    /// target.
+    fn predict(&self, table: &QualityTable, low: i32, high: i32, factor: f64) -> i32 {
+        let metric = self.options.metric;
+        let target = metric.distance(self.target);
+        let error =
+            |quality: i32| (factor * metric.distance(table.target(quality as u8)) - target).abs();
+        (low..=high)
+            .min_by(|a, b| error(*a).partial_cmp(&error(*b)).unwrap())
+            .unwrap_or(low)
+    }
+
+    /// Whether `candidate` meets both the target and the region ceiling.
+    fn meets(&self, candidate: &Candidate) -> bool {
+        let metric = self.options.metric;
+        metric.distance(candidate.score) <= metric.distance(self.target) && candidate.region_ok
+    }
+
+    /// Keep track of the best candidate and return whether quality of `candidate` is too low.
+    fn consider(&mut self, candidate: Candidate) -> bool {
+        match self.options.max_size {
+            // Size grows with quality, so the highest quality that fits the budget is the last
+            // one accepted by the search.
+            Some(max_size) => {
+                let fits = candidate.buffer.len() as u64 <= max_size;
+                if fits {
+                    self.best = Some(candidate);
+                }
+                fits
+            }
+            None => {
+                // Last steps of the binary search are pretty close to each other, so the final
+                // step may not actually have score closest to the target. Instead of using the
+                // last step, keep track of the best attempt so far.
+                let meets = self.meets(&candidate);
+                let better = match &self.best {
+                    // Strict mode never prefers a candidate that misses the target, and among
+                    // the ones that meet it, the smallest is the best.
+                    Some(best) if self.options.strict.is_some() => {
+                        match (meets, self.meets(best)) {
+                            (true, true) => candidate.buffer.len() < best.buffer.len(),
+                            (true, false) => true,
+                            (false, true) => false,
+                            (false, false) => candidate.closer(best, self.target),
+                        }
+                    }
+                    Some(best) => candidate.closer(best, self.target),
+                    None => true,
+                };
+                if better {
+                    self.best = Some(candidate);
+                }
+                !meets
+            }
+        }
+    }
+}
+
+/// Compress `image` with `quality` and compare it to the original. `min` and `max` are the bounds
+/// of the search reported with the attempt.
+#[allow(clippy::too_many_arguments)]
+fn evaluate(
+    image: &Image,
+    attr: &dyn Metric,
+    compress: &LossyCompressor,
+    options: &OptimizeOptions,
+    chroma_subsampling: ChromaSubsampling,
+    quality: u8,
+    (min, max): (u8, u8),
+    fast: bool,
+    progress: &mut Progress,
+) -> Result<Candidate, String> {
+    let metric = options.metric;
+    let max_region = options.region_ceiling();
+
+    let (compressed, buffer) = compress(image, quality, chroma_subsampling)?;
+
+    let (score, region) = match max_region {
+        Some(_) => attr.compare_regions(&compressed, options.tile_size),
+        None => attr.compare(&compressed).map(|score| (score, None)),
+    }
+    .ok_or_else(|| "Failed to compare images".to_string())?;
+    let region_ok = match (max_region, region) {
+        (Some(max_region), Some(region)) => metric.distance(region) <= metric.distance(max_region),
+        (Some(_), None) => {
+            return Err(format!("{} doesn't support region ceiling", metric.name()));
+        }
+        (None, _) => true,
+    };
+
+    let attempt = Attempt {
+        format: options.format,
+        quality: Some(quality),
+        chroma_subsampling: match options.chroma_subsampling {
+            ChromaSubsamplingOption::None => None,
+            _ => Some(chroma_subsampling),
+        },
+        score,
+        region,
+        size: buffer.len(),
+        fast,
+    };
+    options.observer.event(&Event::Attempt {
+        attempt: &attempt,
+        metric,
+        bounds: Some((min, max)),
+        original_size: progress.original_size,
+    });
+    progress.attempts.push(attempt);
+
+    Ok(Candidate {
+        quality,
+        score,
+        region_ok,
+        buffer,
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn find_image(
+    image: &Image,
+    attr: &dyn Metric,
+    lossy_compress: &LossyCompressor,
+    fast_compress: Option<&LossyCompressor>,
+    options: &OptimizeOptions,
+    target: f64,
+    chroma_subsampling: ChromaSubsampling,
+    progress: &mut Progress,
+) -> Result<Option<Candidate>, String> {
+    let mut evaluate = |compress, quality, bounds, fast| {
+        evaluate(
+            image,
+            attr,
+            compress,
+            options,
+            chroma_subsampling,
+            quality,
+            bounds,
+            fast,
+            progress,
+        )
+    };
+
+    // Compress image with different qualities and find which is closest to the target score. If
+    // the format has a fast encoder, it's used for the search and the full encoder only checks the
+    // result and its neighbor towards the target.
+    let table = options
+        .table(options.format)
+        .filter(|table| table.metric() == options.metric);
+    let mut search = Search::new(options, target);
+    let compress = fast_compress.unwrap_or(lossy_compress);
+    search.run(table, None, |quality, bounds| {
+        evaluate(compress, quality, bounds, fast_compress.is_some())
+    })?;
+
+    if fast_compress.is_some() {
+        let seed = search.best.as_ref().map(|best| best.quality);
+        search = Search::new(options, target);
+        let evaluate = |quality, bounds| evaluate(lossy_compress, quality, bounds, false);
+        match seed {
+            Some(seed) => search.refine(seed, evaluate)?,
+            None => search.run(table, None, evaluate)?,
+        }
+    }
+
+    // Strict mode gives up only after the maximum quality has been tried, even if it exceeds
+    // `max_encodes`.
+    if options.strict.is_some() && options.max_size.is_none() {
+        let max = options.max_quality;
+        let missed = match &search.best {
+            Some(best) => !search.meets(best) && best.quality < max,
+            None => true,
+        };
+        if missed {
+            search.consider(evaluate(lossy_compress, max, (max, max), false)?);
+        }
+    }
+
+    Ok(search.best)
+}
+
+/// Compress `image` to the output format with the quality closest to the target, or with the
+/// highest quality that fits `max_size` if a size budget is given.
+///
+/// `original_size` is the size of the input file in bytes and is only used to report progress.
+/// Zero leaves the size relative to the input out of the progress.
+pub fn optimize(
+    image: &Image,
+    options: &OptimizeOptions,
+    original_size: u64,
+) -> Result<OptimizeResult, String> {
+    let (lossy_compress, lossless_compress) = compressors(options.format);
+    let fast_compress = if options.fast_search {
+        fast_compressor(options.format)
+    } else {
+        None
+    };
+    let target = options.score(options.target, options.format)?;
+    let metric = options.metric;
+    options.observer.event(&Event::Search {
+        format: options.format,
+        metric,
+        target,
+    });
+
+    let attr = match options.region_ceiling() {
+        Some(_) => options.metric.prepare_regions(image),
+        None => options.metric.prepare(image),
+    }
+    .ok_or_else(|| "Failed to prepare metric".to_string())?;
+
+    let samplings = match options.chroma_subsampling {
+        ChromaSubsamplingOption::Auto => vec![
+            ChromaSubsampling::_444,
+            ChromaSubsampling::_422,
+            ChromaSubsampling::_420,
+        ],
+        ChromaSubsamplingOption::Manual(sampling) => vec![sampling],
+        ChromaSubsamplingOption::None => vec![ChromaSubsampling::_444],
+    };
+
+    let lossless_compress = lossless_compress.filter(|_| options.lossless);
+
+    // Searches with different chroma subsamplings and lossless compression are independent, so
+    // they run concurrently. Attempts are reported as they're made, but the results are
+    // processed in a fixed order to keep the choice deterministic.
+    let (searches, lossless) = in_pool(options.jobs, || {
+        rayon::join(
+            || {
+                samplings
+                    .par_iter()
+                    .map(|&sampling| {
+                        let mut progress = Progress {
+                            original_size,
+                            attempts: Vec::new(),
+                        };
+                        let candidate = find_image(
+                            image,
+                            attr.as_ref(),
+                            &lossy_compress,
+                            fast_compress.as_ref(),
+                            options,
+                            target,
+                            sampling,
+                            &mut progress,
+                        );
+                        (sampling, progress, candidate)
+                    })
+                    .collect::<Vec<_>>()
+            },
+            || {
+                lossless_compress.as_ref().map(|compress| {
+                    let (_, buffer) = compress(image)?;
+                    let attempt = Attempt {
+                        format: options.format,
+                        quality: None,
+                        chroma_subsampling: None,
+                        score: metric.perfect(),
+                        region: None,
+                        size: buffer.len(),
+                        fast: false,
+                    };
+                    options.observer.event(&Event::Attempt {
+                        attempt: &attempt,
+                        metric,
+                        bounds: None,
+                        original_size,
+                    });
+                    Ok::<_, String>((attempt, buffer))
+                })
+            },
+        )
+    })?;
+
+    options.observer.event(&Event::Searched {
+        format: options.format,
+        attempts: searches
+            .iter()
+            .map(|(_, progress, _)| progress.attempts.len())
+            .sum::<usize>()
+            + matches!(lossless, Some(Ok(_))) as usize,
+    });
+
+    let strict = match options.max_size {
+        Some(_) => None,
+        None => options.strict,
+    };
+    let meets = |candidate: &Candidate| {
+        candidate.region_ok && options.within_tolerance(candidate.score, target)
+    };
+
+    let mut candidates = Vec::new();
+    let mut attempts = Vec::new();
+    for (sampling, progress, candidate) in searches {
+        attempts.extend(progress.attempts);
+        if let Some(candidate) = candidate? {
+            candidates.push((candidate, sampling));
+        }
+    }
+
+    // Choose the smallest candidate that meets the target. If none does, choose the closest one.
+    // With a size budget, all candidates fit and the best quality is chosen.
+    let mut chosen: Option<usize> = None;
+    for (i, (candidate, _)) in candidates.iter().enumerate() {
+        let better = match chosen {
+            Some(j) => {
+                let best = &candidates[j].0;
+                match options.max_size {
+                    Some(_) => metric.distance(candidate.score) < metric.distance(best.score),
+                    None => match (meets(candidate), meets(best)) {
+                        (true, true) => candidate.buffer.len() < best.buffer.len(),
+                        (true, false) => true,
+                        (false, true) => false,
+                        (false, false) => candidate.closer(best, target),
+                    },
+                }
+            }
+            None => true,
+        };
+        if better {
+            chosen = Some(i);
+        }
+    }
+    // In strict mode, the fallback is used if even the best candidate misses the target.
+    let missed = strict.is_some()
+        && match chosen {
+            Some(i) => !meets(&candidates[i].0),
+            None => true,
+        };
+    if let (Some(i), false) = (chosen, missed) {
+        if !candidates[i].0.region_ok {
+            log::warn!(
+                "worst region exceeds the ceiling even with quality {}",
+                candidates[i].0.quality
+            );
+        }
+    }
+
+    let mut entries: Vec<(OptimizeResult, bool)> = candidates
+        .into_iter()
+        .map(|(candidate, sampling)| {
+            let met_target = options.max_size.is_some() || meets(&candidate);
+            let result = OptimizeResult {
+                format: options.format,
+                quality: Some(candidate.quality),
+                score: candidate.score,
+                chroma_subsampling: match options.chroma_subsampling {
+                    ChromaSubsamplingOption::None => None,
+                    _ => Some(sampling),
+                },
+                lossless: false,
+                buffer: candidate.buffer,
+                rejected: Vec::new(),
+                original: false,
+                attempts: Vec::new(),
+            };
+            (result, met_target)
+        })
+        .collect();
+
+    // Lossless compression is tried if the format supports it. For example, lossless WebP can
+    // sometimes be smaller than lossy WebP for non-photographic images.
+    if let Some(lossless) = lossless {
+        let (attempt, b) = lossless?;
+        attempts.push(attempt);
+        let fits = match options.max_size {
+            Some(max_size) => b.len() as u64 <= max_size,
+            None => true,
+        };
+        let better = match (options.max_size, chosen) {
+            (Some(_), _) | (None, None) => fits,
+            (None, Some(_)) if missed => strict == Some(Fallback::Lossless),
+            (None, Some(i)) => b.len() < entries[i].0.buffer.len(),
+        };
+        if better {
+            chosen = Some(entries.len());
+        }
+        entries.push((
+            OptimizeResult {
+                format: options.format,
+                quality: None,
+                score: metric.perfect(),
+                chroma_subsampling: None,
+                lossless: true,
+                buffer: b,
+                rejected: Vec::new(),
+                original: false,
+                attempts: Vec::new(),
+            },
+            fits,
+        ));
+    }
+
+    let original = missed
+        && match chosen {
+            Some(i) => !entries[i].0.lossless,
+            None => true,
+        };
+    if missed {
+        log::warn!(
+            "target can't be met even with quality {}, {}",
+            options.max_quality,
+            if original {
+                "keeping the original image"
+            } else {
+                "using lossless compression"
+            }
+        );
+    }
+
+    let mut best = match (chosen, options.max_size) {
+        _ if original => OptimizeResult {
+            format: options.format,
+            quality: None,
+            score: metric.perfect(),
+            chroma_subsampling: None,
+            lossless: false,
+            buffer: Vec::new(),
+            rejected: Vec::new(),
+            original: true,
+            attempts: Vec::new(),
+        },
+        (Some(i), _) => entries.remove(i).0,
+        (None, Some(max_size)) => {
+            return Err(format!(
+                "output doesn't fit in {} bytes even with the minimum quality {}",
+                max_size, options.min_quality
+            ));
+        }
+        (None, None) => return Err("no candidates to choose from".to_string()),
+    };
+    best.rejected = entries
+        .into_iter()
+        .map(|(result, met_target)| Rejected {
+            quality: result.quality,
+            score: result.score,
+            chroma_subsampling: result.chroma_subsampling,
+            size: result.buffer.len(),
+            met_target,
+        })
+        .collect();
+    best.attempts = attempts;
+    for rejected in &best.rejected {
+        options
+            .observer
+            .event(&Event::Rejected { rejected, metric });
+    }
+
+    if let Some(max_size) = options.max_size {
+        if let Some(floor) = options.floor {
+            let max_score = options.score(floor, options.format)?;
+            if options.metric.distance(best.score) > options.metric.distance(max_score) {
+                let label = options.metric.label();
+                return Err(format!(
+                    "output fits in {} bytes only with {:.6} {} which is worse than {:.6} {}",
+                    max_size, best.score, label, max_score, label
+                ));
+            }
+        }
+    }
+
+    Ok(best)
+}
+
+/// Run `f` in the current rayon thread pool, or outside of one, in a new pool of `jobs` threads.
+/// Callers optimizing many images build their pools once and run each image in one of them.
+fn in_pool<T: Send>(jobs: usize, f: impl FnOnce() -> T + Send) -> Result<T, String> {
+    match rayon::current_thread_index() {
+        Some(_) => Ok(f()),
+        None => {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(jobs)
+                .build()
+                .map_err(|err| err.to_string())?;
+            Ok(pool.install(f))
+        }
+    }
+}
+
+/// Run the search for each of `formats` that can represent `image` and pick the smallest output
+/// that meets the target. If no output meets the target, the one closest to it is picked.
+///
+/// Besides the chosen result, output size or error of every tried format is returned in the order
+/// of `formats`.
+pub fn optimize_auto(
+    image: &Image,
+    formats: &[Format],
+    options: &OptimizeOptions,
+    original_size: u64,
+) -> AutoResult {
+    // Searches of all formats share the pool.
+    in_pool(options.jobs, || {
+        optimize_formats(image, formats, options, original_size)
+    })?
+}
+
+fn optimize_formats(
+    image: &Image,
+    formats: &[Format],
+    options: &OptimizeOptions,
+    original_size: u64,
+) -> AutoResult {
+    // Chosen result, the ratio of its distance to the target of its format and whether it meets
+    // the target. Targets differ between formats, so results that miss the target are compared by
+    // the ratio.
+    let mut best: Option<(OptimizeResult, f64, bool)> = None;
+    let mut sizes = Vec::new();
+    // Result asking to keep the original image in case no format meets the target in strict mode.
+    let mut original = None;
+    let mut attempts = Vec::new();
+
+    for &format in formats {
+        if image.has_alpha() && !format.supports_transparency() {
+            continue;
+        }
+
+        let target = match options.score(options.target, format) {
+            Ok(target) => target,
+            Err(err) => {
+                sizes.push((format, Err(err)));
+                continue;
+            }
+        };
+        let mut options = options.clone();
+        options.format = format;
+        if !format.supports_chroma_subsampling() {
+            options.chroma_subsampling = ChromaSubsamplingOption::None;
+        }
+
+        let result = optimize(image, &options, original_size);
+        if let Ok(result) = &result {
+            attempts.extend(result.attempts.iter().cloned());
+        }
+        let result = match result {
+            Ok(result) if result.original => {
+                sizes.push((format, Err("target can't be met".to_string())));
+                original = Some(result);
+                continue;
+            }
+            Ok(result) => result,
+            Err(err) => {
+                sizes.push((format, Err(err)));
+                continue;
+            }
+        };
+        sizes.push((format, Ok(result.buffer.len())));
+
+        let ratio = options.metric.distance(result.score) / options.metric.distance(target);
+        let meets = options.max_size.is_some() || options.within_tolerance(result.score, target);
+        let better = match &best {
+            None => true,
+            Some((best, best_ratio, best_meets)) => match (meets, best_meets) {
+                (true, true) => result.buffer.len() < best.buffer.len(),
+                (true, false) => true,
+                (false, true) => false,
+                (false, false) => ratio < *best_ratio,
+            },
+        };
+        if better {
+            best = Some((result, ratio, meets));
+        }
+    }
+
+    match (best, original) {
+        (Some((mut best, _, _)), _) | (None, Some(mut best)) => {
+            best.attempts = attempts;
+            Ok((best, sizes))
+        }
+        (None, None) if sizes.is_empty() => {
+            Err("none of the output formats can represent the image".to_string())
+        }
+        (None, None) => Err("failed to compress image to any of the output formats".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Observer keeping the events as text.
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl Observer for Recorder {
+        fn event(&self, event: &Event) {
+            self.events.lock().unwrap().push(event.to_string());
+        }
+    }
+
+    fn image() -> Image {
+        decode(
+            Format::PNG,
+            &std::fs::read("images/image1-original.png").unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn optimizes_without_original_size() {
+        let recorder = Arc::new(Recorder::default());
+        let mut options = OptimizeOptions::new(Format::JPEG);
+        options.observer = recorder.clone();
+        let result = optimize(&image(), &options, 0).unwrap();
+
+        assert_eq!(result.format, Format::JPEG);
+        let quality = result.quality.unwrap();
+        assert!((options.min_quality..=options.max_quality).contains(&quality));
+        assert_eq!(Format::from_magic(&result.buffer), Some(Format::JPEG));
+        assert!(!result.attempts.is_empty());
+        let events = recorder.events.lock().unwrap();
+        assert!(events[0].starts_with("trying jpeg"));
+        assert!(events.iter().all(|event| !event.contains("% of original")));
+    }
+
+    /// Observer noting whether attempts are delivered from the threads of the search.
+    #[derive(Default)]
+    struct ThreadRecorder {
+        events: Mutex<Vec<(String, bool)>>,
+    }
+
+    impl Observer for ThreadRecorder {
+        fn event(&self, event: &Event) {
+            let in_search = rayon::current_thread_index().is_some();
+            self.events
+                .lock()
+                .unwrap()
+                .push((event.to_string(), in_search));
+        }
+    }
+
+    #[test]
+    fn delivers_attempts_during_search() {
+        let recorder = Arc::new(ThreadRecorder::default());
+        let mut options = OptimizeOptions::new(Format::WEBP);
+        options.jobs = 2;
+        options.observer = recorder.clone();
+        let result = optimize(&image(), &options, 0).unwrap();
+
+        let events = recorder.events.lock().unwrap();
+        let searched = events
+            .iter()
+            .position(|(event, _)| event.starts_with("searched webp"))
+            .unwrap();
+        assert_eq!(
+            events[searched].0,
+            format!("searched webp with {} attempts", result.attempts.len())
+        );
+        // Attempts come from the search itself and nothing is delivered from there afterwards.
+        assert_eq!(searched, result.attempts.len() + 1);
+        assert!(events[1..searched].iter().all(|(_, in_search)| *in_search));
+        assert!(events[searched..].iter().all(|(_, in_search)| !in_search));
+    }
+
+    #[test]
+    fn uses_current_pool() {
+        let threads = || rayon::current_num_threads();
+        assert_eq!(in_pool(3, threads), Ok(3));
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+        assert_eq!(pool.install(|| in_pool(3, threads)), Ok(2));
+    }
+
+    #[test]
+    fn allows_tolerance_unless_strict() {
+        let mut options = OptimizeOptions::new(Format::JPEG);
+        options.tolerance = 0.05;
+        assert!(options.within_tolerance(0.0104, 0.01));
+        assert!(!options.within_tolerance(0.0106, 0.01));
+        options.strict = Some(Fallback::Lossless);
+        assert!(!options.within_tolerance(0.0104, 0.01));
+        assert!(options.within_tolerance(0.01, 0.01));
+    }
+
+    #[test]
+    fn auto_chooses_smallest_format_within_tolerance() {
+        // At quality 70, WebP is smaller than JPEG but further from the original.
+        let image = image();
+        let mut options = OptimizeOptions::new(Format::JPEG);
+        options.min_quality = 70;
+        options.max_quality = 70;
+        assert_eq!(options.tolerance, 0.05);
+        let formats = [Format::JPEG, Format::WEBP];
+        let results: Vec<OptimizeResult> = formats
+            .iter()
+            .map(|&format| {
+                let mut options = options.clone();
+                options.format = format;
+                optimize(&image, &options, 0).unwrap()
+            })
+            .collect();
+        let (jpeg, webp) = (&results[0], &results[1]);
+        assert!(webp.buffer.len() < jpeg.buffer.len());
+        assert!(webp.score > jpeg.score);
+
+        // WebP misses the target by less than the tolerance, so it's chosen for its size even
+        // though JPEG meets the target.
+        options.target = Target::Score(webp.score / 1.03);
+        assert!(jpeg.score < webp.score / 1.03);
+        let (result, _) = optimize_auto(&image, &formats, &options, 0).unwrap();
+        assert_eq!(result.format, Format::WEBP);
+
+        // Just outside the tolerance, WebP is rejected in favor of the larger JPEG.
+        options.target = Target::Score(webp.score / 1.07);
+        assert!(jpeg.score < webp.score / 1.07);
+        let (result, _) = optimize_auto(&image, &formats, &options, 0).unwrap();
+        assert_eq!(result.format, Format::JPEG);
+    }
+
+    #[test]
+    fn refines_fast_search_with_two_full_encodes() {
+        for format in [Format::JPEG, Format::WEBP] {
+            let mut options = OptimizeOptions::new(format);
+            options.chroma_subsampling = ChromaSubsamplingOption::Manual(ChromaSubsampling::_420);
+            let result = optimize(&image(), &options, 0).unwrap();
+            let full: Vec<u8> = result
+                .attempts
+                .iter()
+                .filter(|attempt| !attempt.fast)
+                .filter_map(|attempt| attempt.quality)
+                .collect();
+            assert!((1..=2).contains(&full.len()), "{} full encodes", full.len());
+            if let [seed, step] = full[..] {
+                assert_eq!((seed as i32 - step as i32).abs(), 1);
+            }
+            assert!(full.contains(&result.quality.unwrap()));
+        }
+    }
+}
@@ -54,6 +54,35 @@ fn random_file(root: impl AsRef<Path>) -> std::io::Result<(PathBuf, File)> {
     }
 }
 
+/// Whether `path` is a temporary file created by `Output::overwrite_file`.
+pub fn is_temporary_file(path: impl AsRef<Path>) -> bool {
+    let name = match path.as_ref().file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        None => return false,
+    };
+    match name
+        .strip_prefix(".pio-")
+        .and_then(|name| name.strip_suffix(".tmp"))
+    {
+        Some(random) => random.len() == 16 && random.chars().all(|c| c.is_ascii_alphanumeric()),
+        None => false,
+    }
+}
+
+/// Remove temporary files left behind in `dir` by `Output::overwrite_file` when the process was
+/// killed before finishing. Returns the number of removed files.
+pub fn remove_temporary_files(dir: impl AsRef<Path>) -> std::io::Result<usize> {
+    let mut removed = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if is_temporary_file(&path) && path.is_file() {
+            std::fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
 fn file_directory(path: impl AsRef<Path>) -> PathBuf {
     match path.as_ref().parent() {
         Some(parent) => {
@@ -0,0 +1,171 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! `<picture>` snippets and JSON manifests describing the variants of responsive image sets.
+
+use serde_json::{json, Value};
+
+use crate::common::Format;
+use crate::srcset::{Source, Variant};
+
+/// Manifest of all `sources` written to the output directory.
+pub fn manifest(sources: &[Source]) -> Value {
+    json!({ "sources": sources.iter().map(source_manifest).collect::<Vec<_>>() })
+}
+
+/// Manifest entry of `source` listing its variants.
+pub fn source_manifest(source: &Source) -> Value {
+    let variants = source
+        .variants
+        .iter()
+        .map(|variant| {
+            json!({
+                "path": variant.path.display().to_string(),
+                "format": variant.format.ext(),
+                "mime": variant.format.mime(),
+                "width": variant.width,
+                "height": variant.height,
+                "size": variant.size,
+                "hash": variant.hash,
+                "quality": variant.quality,
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "input": source.input.display().to_string(),
+        "width": source.width,
+        "height": source.height,
+        "variants": variants,
+        "skipped_widths": source.skipped_widths,
+    })
+}
+
+/// `<picture>` element of `source` with a `<source>` for each format but the last, which is used
+/// by the `<img>` fallback. Paths are relative to the directory of the variants.
+pub fn snippet(source: &Source, sizes: &str) -> String {
+    let mut groups: Vec<(Format, Vec<&Variant>)> = Vec::new();
+    for variant in &source.variants {
+        match groups.last_mut() {
+            Some((format, variants)) if *format == variant.format => variants.push(variant),
+            _ => groups.push((variant.format, vec![variant])),
+        }
+    }
+    let srcset = |variants: &[&Variant]| {
+        variants
+            .iter()
+            .map(|variant| {
+                let name = variant.path.file_name().unwrap_or_default();
+                format!("{} {}w", name.to_string_lossy(), variant.width)
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let mut html = String::from("<picture>\n");
+    let (_, fallback) = match groups.split_last() {
+        Some((fallback, sources)) => {
+            for (format, variants) in sources {
+                html += &format!(
+                    "  <source type=\"{}\" srcset=\"{}\" sizes=\"{}\">\n",
+                    format.mime(),
+                    escape_html(&srcset(variants)),
+                    escape_html(sizes)
+                );
+            }
+            fallback
+        }
+        None => return String::new(),
+    };
+    let largest = fallback[fallback.len() - 1];
+    html += &format!(
+        "  <img src=\"{}\" srcset=\"{}\" sizes=\"{}\" width=\"{}\" height=\"{}\" alt=\"\">\n",
+        escape_html(
+            &largest
+                .path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+        ),
+        escape_html(&srcset(fallback)),
+        escape_html(sizes),
+        largest.width,
+        largest.height
+    );
+    html + "</picture>\n"
+}
+
+fn escape_html(input: &str) -> String {
+    input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn variant(path: &str, format: Format, width: usize) -> Variant {
+        Variant {
+            path: PathBuf::from(path),
+            format,
+            width,
+            height: width / 2,
+            size: 0,
+            hash: String::new(),
+            quality: None,
+        }
+    }
+
+    #[test]
+    fn writes_source_for_each_format_but_fallback() {
+        let source = Source {
+            input: PathBuf::from("a&b.png"),
+            width: 800,
+            height: 400,
+            variants: vec![
+                variant("dir/a&b-400w.webp", Format::WEBP, 400),
+                variant("dir/a&b-800w.webp", Format::WEBP, 800),
+                variant("dir/a&b-400w.jpeg", Format::JPEG, 400),
+                variant("dir/a&b-800w.jpeg", Format::JPEG, 800),
+            ],
+            skipped_widths: vec![1600],
+            failed: 0,
+        };
+        assert_eq!(
+            snippet(&source, "(min-width: 800px) 50vw, \"100vw\""),
+            "<picture>\n  \
+             <source type=\"image/webp\" srcset=\"a&amp;b-400w.webp 400w, a&amp;b-800w.webp 800w\" \
+             sizes=\"(min-width: 800px) 50vw, &quot;100vw&quot;\">\n  \
+             <img src=\"a&amp;b-800w.jpeg\" srcset=\"a&amp;b-400w.jpeg 400w, a&amp;b-800w.jpeg 800w\" \
+             sizes=\"(min-width: 800px) 50vw, &quot;100vw&quot;\" width=\"800\" height=\"400\" \
+             alt=\"\">\n\
+             </picture>\n"
+        );
+
+        let manifest = source_manifest(&source);
+        assert_eq!(
+            manifest.get("skipped_widths"),
+            Some(&vec![1600usize].into())
+        );
+        match manifest.get("variants") {
+            Some(Value::Array(variants)) => assert_eq!(variants.len(), 4),
+            _ => panic!("expected variants"),
+        }
+    }
+
+    #[test]
+    fn writes_nothing_without_variants() {
+        let source = Source {
+            input: PathBuf::from("a.png"),
+            width: 100,
+            height: 100,
+            variants: Vec::new(),
+            skipped_widths: vec![200],
+            failed: 0,
+        };
+        assert_eq!(snippet(&source, "100vw"), "");
+    }
+}
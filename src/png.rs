@@ -27,10 +27,10 @@ pub fn read(buffer: &[u8]) -> ReadResult {
         .unwrap_or(1);
 
     if let Ok(icc) = decoder.get_icc() {
-        eprintln!("transforming to srgb...");
         match lcms2::Profile::new_icc(&icc) {
             Ok(profile) => {
                 if !is_srgb(&profile) {
+                    log::debug!("transforming RGBA to sRGB");
                     let transform = lcms2::Transform::new(
                         &profile,
                         lcms2::PixelFormat::RGBA_8,
@@ -43,7 +43,7 @@ pub fn read(buffer: &[u8]) -> ReadResult {
                 }
             }
             Err(err) => {
-                eprintln!("Failed to read ICC profile: {}", err);
+                log::warn!("failed to read ICC profile: {}", err);
             }
         }
     }
@@ -0,0 +1,147 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Progress of the optimization for library callers.
+
+use std::fmt;
+
+use crate::common::Format;
+use crate::metric::MetricKind;
+use crate::optimize::{Attempt, Rejected};
+
+/// Step of the quality search.
+pub enum Event<'a> {
+    /// Search for `format` started.
+    Search {
+        format: Format,
+        metric: MetricKind,
+        target: f64,
+    },
+    /// Compression was tried. `bounds` are the qualities between which the search was narrowed
+    /// down when the attempt was made, or `None` for lossless compression.
+    Attempt {
+        attempt: &'a Attempt,
+        metric: MetricKind,
+        bounds: Option<(u8, u8)>,
+        original_size: u64,
+    },
+    /// Searches for `format` finished after `attempts` compressions. Attempts of the format are
+    /// no longer delivered after this.
+    Searched { format: Format, attempts: usize },
+    /// Candidate was compressed but not chosen.
+    Rejected {
+        rejected: &'a Rejected,
+        metric: MetricKind,
+    },
+}
+
+/// Receives progress of the optimization. Events are delivered as they happen. Searches with
+/// different chroma subsamplings run concurrently, so their attempts may interleave and arrive
+/// from different threads until `Event::Searched`.
+pub trait Observer: Send + Sync {
+    fn event(&self, event: &Event);
+}
+
+/// Observer writing events to the `log` crate on the info level.
+pub struct LogObserver;
+
+impl Observer for LogObserver {
+    fn event(&self, event: &Event) {
+        log::info!("{}", event);
+    }
+}
+
+impl fmt::Display for Event<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Search {
+                format,
+                metric,
+                target,
+            } => write!(
+                f,
+                "trying {}, target {}: {}",
+                format.ext(),
+                metric.name(),
+                target
+            ),
+            Event::Attempt {
+                attempt,
+                metric,
+                original_size,
+                ..
+            } => {
+                write!(f, "{}", attempt.format.ext())?;
+                if let Some(sampling) = attempt.chroma_subsampling {
+                    write!(f, " {}", sampling)?;
+                }
+                write!(f, " {}", describe_attempt(attempt, *metric, *original_size))
+            }
+            Event::Searched { format, attempts } => {
+                write!(f, "searched {} with {} attempts", format.ext(), attempts)
+            }
+            Event::Rejected { rejected, metric } => {
+                write!(f, "rejected {}", rejected.describe(*metric))
+            }
+        }
+    }
+}
+
+/// Quality, score and size of `attempt` relative to `original_size`. The relative size is omitted
+/// if `original_size` is zero.
+fn describe_attempt(attempt: &Attempt, metric: MetricKind, original_size: u64) -> String {
+    format!(
+        "{}  {:.6} {}{}{}",
+        match attempt.quality {
+            Some(quality) => format!("{:>3} quality", quality),
+            None => "   lossless".to_string(),
+        },
+        attempt.score,
+        metric.label(),
+        match attempt.region {
+            Some(region) => format!("  {:.6} worst", region),
+            None => String::new(),
+        },
+        match original_size {
+            0 => String::new(),
+            _ => format!(
+                "  {:>3} % of original",
+                100 * attempt.size as u64 / original_size
+            ),
+        },
+    )
+}
+
+/// Line showing `attempt` on a bar of qualities from 0 to 100 with the bounds of the search.
+/// Attempts with the fast encoder are marked with lowercase `o`.
+pub fn search_bar(
+    attempt: &Attempt,
+    metric: MetricKind,
+    bounds: Option<(u8, u8)>,
+    original_size: u64,
+) -> String {
+    let mut line = String::new();
+    match (attempt.quality, bounds) {
+        (Some(quality), Some((min, max))) => {
+            for x in 0..=100 / 4 {
+                if x == quality / 4 {
+                    line.push(if attempt.fast { 'o' } else { 'O' });
+                } else if x == 0 || x == 100 / 4 {
+                    line.push('|');
+                } else if x == min / 4 {
+                    line.push('[');
+                } else if x == max / 4 {
+                    line.push(']');
+                } else if x > min / 4 && x < max / 4 {
+                    line.push('-');
+                } else {
+                    line.push(' ');
+                }
+            }
+        }
+        _ => line.push_str("|                        |"),
+    }
+    line.push(' ');
+    line.push_str(&describe_attempt(attempt, metric, original_size));
+    line
+}
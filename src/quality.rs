@@ -0,0 +1,215 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use std::fmt;
+use std::path::Path;
+
+use lazy_static::lazy_static;
+
+use crate::common::Format;
+use crate::metric::MetricKind;
+
+lazy_static! {
+    static ref JPEG: QualityTable =
+        QualityTable::parse(include_str!("../tables/jpeg-dssim.csv")).unwrap();
+    static ref PNG: QualityTable =
+        QualityTable::parse(include_str!("../tables/png-dssim.csv")).unwrap();
+    static ref WEBP: QualityTable =
+        QualityTable::parse(include_str!("../tables/webp-dssim.csv")).unwrap();
+    static ref JPEG_SSIMULACRA2: QualityTable =
+        QualityTable::parse(include_str!("../tables/jpeg-ssimulacra2.csv")).unwrap();
+}
+
+#[cfg(feature = "avif")]
+lazy_static! {
+    static ref AVIF: QualityTable =
+        QualityTable::parse(include_str!("../tables/avif-dssim.csv")).unwrap();
+}
+
+#[cfg(feature = "butteraugli")]
+lazy_static! {
+    static ref BUTTERAUGLI: QualityTable = QualityTable::new(
+        MetricKind::Butteraugli,
+        (0..=100).map(butteraugli_distance).collect()
+    );
+}
+
+/// Butteraugli distance that JPEG XL encoders target for `quality`, following
+/// `JxlEncoderDistanceFromQuality` of libjxl.
+#[cfg(any(test, feature = "butteraugli"))]
+fn butteraugli_distance(quality: u8) -> f64 {
+    let quality = quality as f64;
+    if quality >= 100.0 {
+        0.0
+    } else if quality >= 30.0 {
+        0.1 + (100.0 - quality) * 0.09
+    } else {
+        53.0 / 3000.0 * quality * quality - 23.0 / 20.0 * quality + 25.0
+    }
+}
+
+/// Mapping from JPEG-like quality values 0-100 to metric targets.
+#[derive(Clone)]
+pub struct QualityTable {
+    metric: MetricKind,
+    values: Vec<f64>,
+}
+
+impl QualityTable {
+    /// Table shipped with pio for the given output format and metric, if any.
+    pub fn builtin(format: Format, metric: MetricKind) -> Option<&'static Self> {
+        match (format, metric) {
+            (Format::JPEG, MetricKind::Dssim) => Some(&JPEG),
+            (Format::PNG, MetricKind::Dssim) => Some(&PNG),
+            (Format::WEBP, MetricKind::Dssim) => Some(&WEBP),
+            #[cfg(feature = "avif")]
+            (Format::AVIF, MetricKind::Dssim) => Some(&AVIF),
+            // JPEG XL support is experimental and its encoder isn't calibrated, so it uses the JPEG
+            // table.
+            #[cfg(feature = "jxl")]
+            (Format::JXL, MetricKind::Dssim) => Some(&JPEG),
+            // SSIMULACRA2 is calibrated against human ratings of different codecs, so the same
+            // score means the same quality regardless of the format.
+            (_, MetricKind::Ssimulacra2) => Some(&JPEG_SSIMULACRA2),
+            // Butteraugli distance is measured in just noticeable differences, so the distances
+            // libjxl maps qualities to apply to every format.
+            #[cfg(feature = "butteraugli")]
+            (_, MetricKind::Butteraugli) => Some(&BUTTERAUGLI),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    /// Parse table in CSV format with columns for quality and metric score. The metric is
+    /// determined by the header line and defaults to DSSIM. Lines starting with `#` are ignored.
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let mut metric = MetricKind::Dssim;
+        let mut values = vec![None; 101];
+        for (i, line) in input.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix("quality,") {
+                metric = MetricKind::from_name(name.trim())
+                    .ok_or_else(|| format!("line {}: unknown metric {}", i + 1, name))?;
+                continue;
+            }
+            let mut columns = line.split(',');
+            let (quality, value) = match (columns.next(), columns.next()) {
+                (Some(quality), Some(value)) => (quality.trim(), value.trim()),
+                _ => return Err(format!("line {}: expected two columns", i + 1)),
+            };
+            let quality = quality
+                .parse::<u8>()
+                .ok()
+                .filter(|quality| *quality <= 100)
+                .ok_or_else(|| format!("line {}: expected quality between 0 and 100", i + 1))?;
+            let value = value
+                .parse::<f64>()
+                .ok()
+                .filter(|value| value.is_finite())
+                .ok_or_else(|| format!("line {}: expected number", i + 1))?;
+            values[quality as usize] = Some(value);
+        }
+        let values = values
+            .into_iter()
+            .enumerate()
+            .map(|(quality, value)| value.ok_or_else(|| format!("missing quality {}", quality)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { metric, values })
+    }
+
+    /// Create table from metric scores of qualities 0-100.
+    pub fn new(metric: MetricKind, values: Vec<f64>) -> Self {
+        assert_eq!(values.len(), 101);
+        Self { metric, values }
+    }
+
+    pub fn metric(&self) -> MetricKind {
+        self.metric
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let input = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+        Self::parse(&input)
+    }
+
+    pub fn target(&self, quality: u8) -> f64 {
+        self.values[quality as usize]
+    }
+}
+
+impl fmt::Display for QualityTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "quality,{}", self.metric.name())?;
+        for (quality, value) in self.values.iter().enumerate() {
+            writeln!(f, "{},{}", quality, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether distances of `table` don't grow with quality.
+    fn is_monotonic(table: &QualityTable) -> bool {
+        let distances: Vec<f64> = (0..=100)
+            .map(|quality| table.metric.distance(table.target(quality)))
+            .collect();
+        distances.windows(2).all(|pair| pair[1] <= pair[0])
+    }
+
+    #[test]
+    fn has_table_for_every_format_and_metric() {
+        for &format in Format::ALL {
+            for &metric in MetricKind::ALL {
+                let table = QualityTable::builtin(format, metric).unwrap();
+                assert_eq!(table.metric(), metric);
+                assert!(is_monotonic(table));
+            }
+        }
+    }
+
+    #[test]
+    fn parses_avif_table() {
+        // The table is checked even if AVIF isn't enabled.
+        let table = QualityTable::parse(include_str!("../tables/avif-dssim.csv")).unwrap();
+        assert_eq!(table.metric(), MetricKind::Dssim);
+        assert!(is_monotonic(&table));
+        assert!(
+            table.target(80)
+                < QualityTable::builtin(Format::JPEG, MetricKind::Dssim)
+                    .unwrap()
+                    .target(80)
+        );
+    }
+
+    #[test]
+    fn maps_quality_to_butteraugli_distance() {
+        assert_eq!(butteraugli_distance(100), 0.0);
+        assert!((butteraugli_distance(90) - 1.0).abs() < 1e-9);
+        assert!((butteraugli_distance(30) - 6.4).abs() < 1e-9);
+        assert!((butteraugli_distance(0) - 25.0).abs() < 1e-9);
+        let distances: Vec<f64> = (0..=100).map(butteraugli_distance).collect();
+        assert!(distances.windows(2).all(|pair| pair[1] < pair[0]));
+    }
+
+    #[test]
+    fn parses_and_writes_tables() {
+        let table = QualityTable::new(
+            MetricKind::Ssimulacra2,
+            (0..=100u8).map(f64::from).collect(),
+        );
+        let parsed = QualityTable::parse(&table.to_string()).unwrap();
+        assert_eq!(parsed.metric(), MetricKind::Ssimulacra2);
+        assert_eq!(parsed.values, table.values);
+
+        assert!(QualityTable::parse("quality,dssim\n0,0.1\n").is_err());
+        assert!(QualityTable::parse("quality,unknown\n").is_err());
+        assert!(QualityTable::parse("101,0.1\n").is_err());
+        assert!(QualityTable::parse("0,nan\n").is_err());
+    }
+}
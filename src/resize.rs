@@ -0,0 +1,273 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Resizing of decoded images. Pixels are resampled in linear light with premultiplied alpha, so
+//! that downscaling neither darkens fine detail nor bleeds color from transparent pixels.
+
+use rayon::prelude::*;
+use rgb::RGBA8;
+
+use crate::common::{linear_to_srgb, srgb_to_linear, Image};
+
+/// How the image is fitted to the dimensions given by `--resize`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Fit {
+    /// Scale the image to fit inside the dimensions keeping its aspect ratio.
+    Contain,
+    /// Scale the image to cover the dimensions and crop the overflow evenly from both sides.
+    Cover,
+}
+
+impl Fit {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "contain" => Some(Self::Contain),
+            "cover" => Some(Self::Cover),
+            _ => None,
+        }
+    }
+}
+
+/// Dimensions the image is resized to. Images are left as they are by default.
+#[derive(Clone, Debug)]
+pub struct ResizeOptions {
+    /// Width to resize to. If only one of `width` and `height` is set, the other follows from the
+    /// aspect ratio.
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub fit: Fit,
+    /// Limits on the dimensions after resizing. Images are only scaled down to meet them.
+    pub max_width: Option<usize>,
+    pub max_height: Option<usize>,
+    /// Amount of unsharp masking applied after resampling, or 0 to not sharpen.
+    pub sharpen: f32,
+}
+
+impl Default for ResizeOptions {
+    fn default() -> Self {
+        Self {
+            width: None,
+            height: None,
+            fit: Fit::Contain,
+            max_width: None,
+            max_height: None,
+            sharpen: 0.0,
+        }
+    }
+}
+
+/// Region of the source image that is resampled.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct Crop {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+impl ResizeOptions {
+    /// Region of an image of `width` × `height` pixels to resample and the dimensions of the
+    /// result, or `None` if the image is left as it is.
+    fn plan(&self, width: usize, height: usize) -> Option<(Crop, usize, usize)> {
+        let scaled = |size: usize, scale: f64| ((size as f64 * scale).round() as usize).max(1);
+        let mut crop = Crop {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        };
+        let (mut out_width, mut out_height) = match (self.width, self.height) {
+            (Some(w), Some(h)) if self.fit == Fit::Cover => {
+                // Crop the source to the aspect ratio of the target.
+                if width * h > w * height {
+                    crop.width = scaled(height, w as f64 / h as f64).min(width);
+                    crop.x = (width - crop.width) / 2;
+                } else {
+                    crop.height = scaled(width, h as f64 / w as f64).min(height);
+                    crop.y = (height - crop.height) / 2;
+                }
+                (w, h)
+            }
+            (Some(w), Some(h)) => {
+                let scale = (w as f64 / width as f64).min(h as f64 / height as f64);
+                (scaled(width, scale), scaled(height, scale))
+            }
+            (Some(w), None) => (w, scaled(height, w as f64 / width as f64)),
+            (None, Some(h)) => (scaled(width, h as f64 / height as f64), h),
+            (None, None) => (width, height),
+        };
+        let scale = [
+            self.max_width.map(|max| max as f64 / out_width as f64),
+            self.max_height.map(|max| max as f64 / out_height as f64),
+        ]
+        .iter()
+        .flatten()
+        .fold(1.0, |scale: f64, &limit| scale.min(limit));
+        if scale < 1.0 {
+            out_width = scaled(out_width, scale);
+            out_height = scaled(out_height, scale);
+        }
+        if crop.width == width
+            && crop.height == height
+            && (out_width, out_height) == (width, height)
+        {
+            None
+        } else {
+            Some((crop, out_width, out_height))
+        }
+    }
+
+    /// Dimensions of an image of `width` × `height` pixels after resizing.
+    pub fn dimensions(&self, width: usize, height: usize) -> (usize, usize) {
+        match self.plan(width, height) {
+            Some((_, width, height)) => (width, height),
+            None => (width, height),
+        }
+    }
+}
+
+fn lanczos3(x: f64) -> f64 {
+    let sinc = |x: f64| {
+        if x == 0.0 {
+            1.0
+        } else {
+            let x = x * std::f64::consts::PI;
+            x.sin() / x
+        }
+    };
+    if x.abs() < 3.0 {
+        sinc(x) * sinc(x / 3.0)
+    } else {
+        0.0
+    }
+}
+
+/// First source pixel and the normalized weights of the source pixels contributing to each of
+/// `dst_len` output pixels resampled from `src_len` pixels starting at `src_start`.
+fn weights(src_start: usize, src_len: usize, dst_len: usize) -> Vec<(usize, Vec<f32>)> {
+    let scale = src_len as f64 / dst_len as f64;
+    // The filter is widened when downscaling so that every source pixel contributes.
+    let filter_scale = scale.max(1.0);
+    let support = 3.0 * filter_scale;
+    (0..dst_len)
+        .map(|i| {
+            let center = (i as f64 + 0.5) * scale;
+            let left = (center - support).floor().max(0.0) as usize;
+            let right = ((center + support).ceil() as usize).min(src_len);
+            let mut weights: Vec<f64> = (left..right)
+                .map(|j| lanczos3((j as f64 + 0.5 - center) / filter_scale))
+                .collect();
+            let sum: f64 = weights.iter().sum();
+            weights.iter_mut().for_each(|weight| *weight /= sum);
+            (
+                src_start + left,
+                weights.into_iter().map(|weight| weight as f32).collect(),
+            )
+        })
+        .collect()
+}
+
+/// Weighted sum of `pixels` starting at `start` with `stride` between the pixels.
+fn convolve(pixels: &[[f32; 4]], start: usize, stride: usize, weights: &[f32]) -> [f32; 4] {
+    let mut sum = [0.0; 4];
+    for (i, weight) in weights.iter().enumerate() {
+        let pixel = pixels[start + i * stride];
+        for c in 0..4 {
+            sum[c] += pixel[c] * weight;
+        }
+    }
+    sum
+}
+
+/// Sharpen premultiplied pixels with an unsharp mask of radius 1.
+fn sharpen(pixels: &mut [[f32; 4]], width: usize, height: usize, amount: f32) {
+    let blurred: Vec<[f32; 4]> = (0..width * height)
+        .into_par_iter()
+        .map(|i| {
+            let (x, y) = (i % width, i / width);
+            let mut sum = [0.0; 4];
+            let mut total = 0.0;
+            for dy in -1..=1_isize {
+                for dx in -1..=1_isize {
+                    let sx = (x as isize + dx).clamp(0, width as isize - 1) as usize;
+                    let sy = (y as isize + dy).clamp(0, height as isize - 1) as usize;
+                    let weight = ((2 - dx.abs()) * (2 - dy.abs())) as f32;
+                    let pixel = pixels[sy * width + sx];
+                    for c in 0..4 {
+                        sum[c] += pixel[c] * weight;
+                    }
+                    total += weight;
+                }
+            }
+            sum.map(|c| c / total)
+        })
+        .collect();
+    pixels
+        .par_iter_mut()
+        .zip(blurred.par_iter())
+        .for_each(|(pixel, blurred)| {
+            // Alpha is left alone to keep edges of transparent regions in place.
+            for c in 0..3 {
+                pixel[c] += amount * (pixel[c] - blurred[c]);
+            }
+        });
+}
+
+/// Resize `image` as given by `options`, or return `None` if the dimensions stay the same.
+pub fn resize(image: &Image, options: &ResizeOptions) -> Option<Image> {
+    let (crop, width, height) = options.plan(image.width, image.height)?;
+    let lut: Vec<f32> = (0..=255).map(srgb_to_linear).collect();
+
+    // Resample rows of the crop first and then the columns of the result.
+    let columns = weights(crop.x, crop.width, width);
+    let rows: Vec<[f32; 4]> = (crop.y..crop.y + crop.height)
+        .into_par_iter()
+        .flat_map_iter(|y| {
+            let row: Vec<[f32; 4]> = image.data[y * image.width..(y + 1) * image.width]
+                .iter()
+                .map(|pixel| {
+                    let a = pixel.a as f32 / 255.0;
+                    [
+                        lut[pixel.r as usize] * a,
+                        lut[pixel.g as usize] * a,
+                        lut[pixel.b as usize] * a,
+                        a,
+                    ]
+                })
+                .collect();
+            columns
+                .iter()
+                .map(|(start, weights)| convolve(&row, *start, 1, weights))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    let mut pixels: Vec<[f32; 4]> = weights(0, crop.height, height)
+        .par_iter()
+        .flat_map_iter(|(start, weights)| {
+            let rows = &rows;
+            (0..width).map(move |x| convolve(rows, start * width + x, width, weights))
+        })
+        .collect();
+
+    if options.sharpen > 0.0 {
+        sharpen(&mut pixels, width, height, options.sharpen);
+    }
+
+    let data = pixels
+        .par_iter()
+        .map(|pixel| {
+            let a = pixel[3].clamp(0.0, 1.0);
+            if a == 0.0 {
+                return RGBA8::new(0, 0, 0, 0);
+            }
+            let color = |c: f32| linear_to_srgb((c / a).clamp(0.0, 1.0));
+            RGBA8::new(
+                color(pixel[0]),
+                color(pixel[1]),
+                color(pixel[2]),
+                (a * 255.0).round() as u8,
+            )
+        })
+        .collect();
+    Some(Image::from_rgba(data, width, height))
+}
@@ -0,0 +1,333 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Optimization service answering HTTP requests served by the `http` module. The output format
+//! is negotiated with the `Accept` header.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use rgb::RGB8;
+use serde_json::json;
+
+use crate::common::{supported_formats, ChromaSubsamplingOption, Format, Image};
+use crate::http::{self, Request, Response};
+use crate::metric::MetricKind;
+use crate::optimize::{decode, optimize, optimize_auto, OptimizeOptions, Target};
+use crate::progress::{Event, Observer};
+
+/// Optimization service answering `POST /` with the optimized image and `GET /health` with its
+/// status.
+pub struct Service {
+    /// Number of images optimized at the moment.
+    active: AtomicUsize,
+    max_concurrency: usize,
+    /// Threads shared by the images optimized at the moment.
+    pool: rayon::ThreadPool,
+}
+
+/// Slot of a request being optimized. The slot is released when dropped.
+struct Slot<'a>(&'a AtomicUsize);
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Observer writing progress of the service only in verbose mode.
+struct VerboseObserver;
+
+impl Observer for VerboseObserver {
+    fn event(&self, event: &Event) {
+        log::debug!("{}", event);
+    }
+}
+
+impl Service {
+    /// Service optimizing up to `max_concurrency` images at a time with `jobs` threads shared by
+    /// them.
+    pub fn new(max_concurrency: usize, jobs: usize) -> Result<Self, String> {
+        Ok(Self {
+            active: AtomicUsize::new(0),
+            max_concurrency,
+            pool: rayon::ThreadPoolBuilder::new()
+                .num_threads(jobs)
+                .build()
+                .map_err(|err| err.to_string())?,
+        })
+    }
+
+    pub fn handle(&self, request: &Request) -> Response {
+        let response = match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/health") => {
+                let status = json!({
+                    "status": "ok",
+                    "active": self.active.load(Ordering::SeqCst),
+                    "max_concurrency": self.max_concurrency,
+                });
+                Response::new(200, "application/json", format!("{}\n", status))
+            }
+            (_, "/health") => {
+                Response::error(405, "use GET to check health").with_header("Allow", "GET")
+            }
+            ("POST", "/") => match self.acquire() {
+                Some(_slot) => self.pool.install(|| self.optimize(request)),
+                None => Response::error(503, "too many images are being optimized, retry later")
+                    .with_header("Retry-After", 1),
+            },
+            (_, "/") => {
+                Response::error(405, "use POST to optimize an image").with_header("Allow", "POST")
+            }
+            _ => Response::error(404, "not found"),
+        };
+        log::info!(
+            "{} {} {} {} -> {} bytes",
+            request.method,
+            request.path,
+            response.status,
+            request.body.len(),
+            response.body.len()
+        );
+        response
+    }
+
+    /// Reserve slot for optimizing an image if the concurrency limit allows.
+    fn acquire(&self) -> Option<Slot<'_>> {
+        let active = self.active.fetch_add(1, Ordering::SeqCst);
+        let slot = Slot(&self.active);
+        if active < self.max_concurrency {
+            Some(slot)
+        } else {
+            None
+        }
+    }
+
+    /// Output formats for `request` with an image in `input_format`. The format is given by the
+    /// `format` parameter. Otherwise the formats named in the `Accept` header are tried together
+    /// with the input format if it's acceptable, and the smallest output is chosen.
+    fn formats(&self, request: &Request, input_format: Format) -> Result<Vec<Format>, Response> {
+        let ranges = request.header("Accept").map(http::media_ranges);
+        let acceptable = |format: Format| match &ranges {
+            Some(ranges) => match http::accepts(ranges, format.mime()) {
+                Some((weight, explicit)) if weight > 0.0 => Some(explicit),
+                _ => None,
+            },
+            None => Some(false),
+        };
+        let formats: Vec<Format> = match request.query("format") {
+            Some("auto") => Format::ALL
+                .iter()
+                .copied()
+                .filter(|&format| acceptable(format).is_some())
+                .collect(),
+            Some(format) => {
+                let format = Format::from_ext(format)
+                    .ok_or_else(|| Response::error(400, &format!("unknown format {}", format)))?;
+                acceptable(format).map(|_| format).into_iter().collect()
+            }
+            None => Format::ALL
+                .iter()
+                .copied()
+                .filter(|&format| match acceptable(format) {
+                    Some(explicit) => explicit || format == input_format,
+                    None => false,
+                })
+                .collect(),
+        };
+        if formats.is_empty() {
+            return Err(Response::error(
+                406,
+                "none of the acceptable formats are supported",
+            ));
+        }
+        Ok(formats)
+    }
+
+    fn optimize(&self, request: &Request) -> Response {
+        let input_format = match Format::from_magic(&request.body) {
+            Some(format) => format,
+            None => {
+                return Response::error(
+                    415,
+                    &format!(
+                        "unknown image format, expected one of: {}",
+                        supported_formats()
+                    ),
+                )
+            }
+        };
+        let formats = match self.formats(request, input_format) {
+            Ok(formats) => formats,
+            Err(response) => return response,
+        };
+        let options = match job_options(
+            |name| request.query(name).map(str::to_string),
+            formats[0],
+            self.pool.current_num_threads(),
+        ) {
+            Ok(options) => options,
+            Err(err) => return Response::error(400, &err),
+        };
+
+        let image = match decode(input_format, &request.body) {
+            Ok(image) => image,
+            Err(err) => return Response::error(400, &format!("failed to read image: {}", err)),
+        };
+        let compressed = match compress(image, &request.body, input_format, &formats, options) {
+            Ok(compressed) => compressed,
+            Err(err) => return Response::error(422, &format!("failed to compress image: {}", err)),
+        };
+
+        let quality = if compressed.original {
+            "original".to_string()
+        } else {
+            match compressed.quality {
+                Some(quality) => quality.to_string(),
+                None => "lossless".to_string(),
+            }
+        };
+        Response::new(200, compressed.format.mime(), compressed.buffer)
+            .with_header("Vary", "Accept")
+            .with_header("X-Pio-Quality", quality)
+            .with_header("X-Pio-Metric", compressed.metric.name())
+            .with_header("X-Pio-Score", format!("{:.6}", compressed.score))
+    }
+}
+
+/// Options of the search for an image optimized by the service or the worker. `param` looks up
+/// the parameters `quality`, `min`, `max` and `max-size`. Defaults match the command-line tool.
+pub fn job_options(
+    param: impl Fn(&str) -> Option<String>,
+    format: Format,
+    jobs: usize,
+) -> Result<OptimizeOptions, String> {
+    let param = |name: &str, max: u64| -> Result<Option<u64>, String> {
+        match param(name) {
+            Some(value) => match value.parse::<u64>() {
+                Ok(value) if value <= max => Ok(Some(value)),
+                _ => Err(format!("invalid {}, expected number up to {}", name, max)),
+            },
+            None => Ok(None),
+        }
+    };
+    let quality = param("quality", 100)?.unwrap_or(85) as u8;
+    let max_size = param("max-size", u64::MAX)?;
+    if max_size == Some(0) {
+        return Err("invalid max-size, expected positive number of bytes".to_string());
+    }
+
+    let mut options = OptimizeOptions::new(format);
+    options.target = Target::Quality(quality);
+    options.max_size = max_size;
+    options.min_quality = match param("min", 100)? {
+        Some(min) => min as u8,
+        None if max_size.is_some() => 0,
+        None => quality.saturating_sub(10),
+    };
+    options.max_quality = match param("max", 100)? {
+        Some(max) => max as u8,
+        None if max_size.is_some() => 100,
+        None => (quality + 10).min(100),
+    };
+    if options.min_quality > options.max_quality {
+        return Err("min must be smaller or equal to max".to_string());
+    }
+    options.jobs = jobs;
+    options.observer = Arc::new(VerboseObserver);
+    Ok(options)
+}
+
+/// Image optimized in memory by the service or the worker.
+pub struct Compressed {
+    pub format: Format,
+    pub buffer: Vec<u8>,
+    pub quality: Option<u8>,
+    pub metric: MetricKind,
+    pub score: f64,
+    /// Input was kept because it's in the chosen format and no larger than the output.
+    pub original: bool,
+}
+
+/// Optimize `image` decoded from `input` choosing the smallest output of `formats`.
+pub fn compress(
+    mut image: Image,
+    input: &[u8],
+    input_format: Format,
+    formats: &[Format],
+    mut options: OptimizeOptions,
+) -> Result<Compressed, String> {
+    if formats.len() == 1 && !formats[0].supports_transparency() {
+        image.alpha_blend(RGB8::new(0xff, 0xff, 0xff));
+    }
+    if formats.len() == 1 && !formats[0].supports_chroma_subsampling() {
+        options.chroma_subsampling = ChromaSubsamplingOption::None;
+    }
+
+    let original_size = input.len() as u64;
+    let result = if formats.len() == 1 {
+        optimize(&image, &options, original_size)?
+    } else {
+        optimize_auto(&image, formats, &options, original_size)?.0
+    };
+
+    let original =
+        result.format == input_format && (result.original || result.buffer.len() >= input.len());
+    Ok(Compressed {
+        format: result.format,
+        buffer: if original {
+            input.to_vec()
+        } else {
+            result.buffer
+        },
+        quality: result.quality,
+        metric: options.metric,
+        score: result.score,
+        original,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(accept: Option<&str>, format: Option<&str>) -> Request {
+        Request {
+            method: "POST".to_string(),
+            path: "/".to_string(),
+            query: format
+                .map(|format| ("format".to_string(), format.to_string()))
+                .into_iter()
+                .collect(),
+            headers: accept
+                .map(|accept| ("Accept".to_string(), accept.to_string()))
+                .into_iter()
+                .collect(),
+            body: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn negotiates_output_format() {
+        let service = Service::new(1, 1).unwrap();
+        let formats = |accept, format| {
+            service
+                .formats(&request(accept, format), Format::PNG)
+                .map_err(|response| response.status)
+        };
+        // Input format is kept unless other formats are accepted explicitly.
+        assert_eq!(formats(None, None), Ok(vec![Format::PNG]));
+        assert_eq!(formats(Some("*/*"), None), Ok(vec![Format::PNG]));
+        assert_eq!(
+            formats(Some("image/webp,*/*;q=0.8"), None),
+            Ok(vec![Format::PNG, Format::WEBP])
+        );
+        assert_eq!(
+            formats(Some("image/jpeg,image/png;q=0"), None),
+            Ok(vec![Format::JPEG])
+        );
+        assert_eq!(formats(Some("*/*"), Some("webp")), Ok(vec![Format::WEBP]));
+        assert_eq!(formats(Some("image/png"), Some("webp")), Err(406));
+        assert_eq!(formats(None, Some("gif")), Err(400));
+    }
+}
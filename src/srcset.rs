@@ -0,0 +1,260 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Responsive image sets: variants of each input at several widths and formats for `srcset`.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::common::{Format, Image};
+use crate::file::{search, Settings};
+use crate::optimize::decode;
+use crate::output::Output;
+use crate::picture;
+use crate::resize::{resize, ResizeOptions};
+
+/// Output of a source image at one width in one format.
+pub struct Variant {
+    /// Path relative to the output directory.
+    pub path: PathBuf,
+    pub format: Format,
+    pub width: usize,
+    pub height: usize,
+    pub size: usize,
+    pub hash: String,
+    pub quality: Option<u8>,
+}
+
+/// Source image and its variants written by `write_srcset`.
+pub struct Source {
+    pub input: PathBuf,
+    pub width: usize,
+    pub height: usize,
+    pub variants: Vec<Variant>,
+    /// Widths that are larger than the source.
+    pub skipped_widths: Vec<usize>,
+    pub failed: usize,
+}
+
+/// Write each of `files` at each of `widths` in each of `formats` under `output_dir` and list the
+/// outputs in a manifest there. Inputs are kept in their own format if no formats are given.
+/// `<picture>` snippets with `sizes` are written next to the variants if `sizes` is given.
+pub fn write_srcset(
+    settings: &Settings,
+    output_dir: &Path,
+    files: &[(PathBuf, PathBuf)],
+    widths: &[usize],
+    formats: Option<&[Format]>,
+    sizes: Option<&str>,
+) -> Result<(), String> {
+    let mut sources = Vec::new();
+    let mut failed = 0;
+    let mut names = BTreeMap::new();
+    for (path, relative) in files {
+        match srcset_file(
+            settings, output_dir, path, relative, widths, formats, sizes, &mut names,
+        ) {
+            Ok(Some(source)) => {
+                failed += source.failed;
+                sources.push(source);
+            }
+            Ok(None) => log::info!("{}: skipped, unknown format", path.display()),
+            Err(err) => {
+                log::error!("{}: {}", path.display(), err);
+                failed += 1;
+            }
+        }
+    }
+
+    let manifest = picture::manifest(&sources);
+    std::fs::create_dir_all(output_dir)
+        .map_err(|err| format!("failed to create output directory: {}", err))?;
+    Output::write_file(output_dir.join("manifest.json"))
+        .and_then(|output| output.write(format!("{}\n", manifest).as_bytes()))
+        .map_err(|err| format!("failed to write manifest: {}", err))?;
+
+    let variants: usize = sources.iter().map(|source| source.variants.len()).sum();
+    log::info!("wrote {} variants of {} images", variants, sources.len());
+    if failed > 0 {
+        Err(format!("failed to write {} of the variants", failed))
+    } else {
+        Ok(())
+    }
+}
+
+/// Decode input at `path` once and write its variants to `relative` under `output_dir`, or
+/// return `None` if the input isn't an image. Variants are ordered by the preference of their
+/// format given by the order of `formats`. `<picture>` snippet with `sizes` and a manifest of the
+/// variants are written next to them if `sizes` is given.
+///
+/// Outputs are named after the file stem of `relative`, so inputs differing only by extension or
+/// by the directory they were given from would overwrite each other. `names` maps the stems
+/// written so far to their inputs and an input whose stem is taken is rejected.
+#[allow(clippy::too_many_arguments)]
+fn srcset_file(
+    settings: &Settings,
+    output_dir: &Path,
+    path: &Path,
+    relative: &Path,
+    widths: &[usize],
+    formats: Option<&[Format]>,
+    sizes: Option<&str>,
+    names: &mut BTreeMap<PathBuf, PathBuf>,
+) -> Result<Option<Source>, String> {
+    let input_buffer =
+        std::fs::read(path).map_err(|err| format!("failed to read input: {}", err))?;
+    let input_format = match Format::from_magic(&input_buffer) {
+        Some(format) => format,
+        None => return Ok(None),
+    };
+    let image = decode(input_format, &input_buffer)
+        .map_err(|err| format!("failed to read input: {}", err))?;
+    let formats = formats.unwrap_or(std::slice::from_ref(&input_format));
+    let stem = relative
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if let Some(other) = names.get(&relative.with_file_name(&stem)) {
+        return Err(format!(
+            "outputs would overwrite those of {}, rename one of the inputs",
+            other.display()
+        ));
+    }
+    names.insert(relative.with_file_name(&stem), path.to_path_buf());
+
+    let mut source = Source {
+        input: path.to_path_buf(),
+        width: image.width,
+        height: image.height,
+        variants: Vec::new(),
+        skipped_widths: Vec::new(),
+        failed: 0,
+    };
+    for &width in widths {
+        if width > image.width {
+            log::info!(
+                "{}: skipped width {}, image is {} pixels wide",
+                path.display(),
+                width,
+                image.width
+            );
+            source.skipped_widths.push(width);
+            continue;
+        }
+        let resize_options = ResizeOptions {
+            width: Some(width),
+            sharpen: settings.resize.sharpen,
+            ..ResizeOptions::default()
+        };
+        let resized = resize(&image, &resize_options);
+        let variant_image = resized.as_ref().unwrap_or(&image);
+        for &format in formats {
+            let variant_path =
+                relative.with_file_name(format!("{}-{}w.{}", stem, width, format.ext()));
+            match write_variant(
+                settings,
+                output_dir,
+                &variant_path,
+                variant_image,
+                resized.is_some(),
+                format,
+                input_format,
+                &input_buffer,
+            ) {
+                Ok(variant) => {
+                    log::info!(
+                        "{}: {} bytes  {:>3} % of original",
+                        output_dir.join(&variant.path).display(),
+                        variant.size,
+                        100 * variant.size / input_buffer.len()
+                    );
+                    source.variants.push(variant);
+                }
+                Err(err) => {
+                    log::error!("{}: {}", output_dir.join(&variant_path).display(), err);
+                    source.failed += 1;
+                }
+            }
+        }
+    }
+    source.variants.sort_by_key(|variant| {
+        let preference = formats.iter().position(|&format| format == variant.format);
+        (preference, variant.width)
+    });
+
+    if let Some(sizes) = sizes {
+        if source.variants.is_empty() {
+            log::warn!("{}: no variants for <picture>", path.display());
+            return Ok(Some(source));
+        }
+        let write = |extension: &str, contents: String| {
+            let path = output_dir.join(relative.with_file_name(format!("{}.{}", stem, extension)));
+            Output::write_file(&path)
+                .and_then(|output| output.write(contents.as_bytes()))
+                .map_err(|err| format!("failed to write {}: {}", path.display(), err))
+        };
+        write("html", picture::snippet(&source, sizes))?;
+        write(
+            "manifest.json",
+            format!("{}\n", picture::source_manifest(&source)),
+        )?;
+    }
+    Ok(Some(source))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn write_variant(
+    settings: &Settings,
+    output_dir: &Path,
+    path: &Path,
+    image: &Image,
+    resized: bool,
+    format: Format,
+    input_format: Format,
+    input_buffer: &[u8],
+) -> Result<Variant, String> {
+    let blended;
+    let image = if !format.supports_transparency() || settings.no_transparency {
+        let mut image = image.clone();
+        image.alpha_blend(settings.background_color);
+        blended = image;
+        &blended
+    } else {
+        image
+    };
+    let (_, result) = search(
+        settings,
+        &settings.options.observer,
+        image,
+        Some(format),
+        input_buffer.len(),
+        resized,
+    )?;
+    let buffer = if !result.original {
+        &result.buffer[..]
+    } else if !resized && format == input_format {
+        input_buffer
+    } else {
+        return Err("target can't be met and the input can't be kept as this variant".to_string());
+    };
+
+    let output_path = output_dir.join(path);
+    if let Some(parent) = output_path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|err| format!("failed to create output directory: {}", err))?;
+    }
+    Output::write_file(&output_path)
+        .and_then(|output| output.write(buffer))
+        .map_err(|err| format!("failed to write output: {}", err))?;
+    Ok(Variant {
+        path: path.to_path_buf(),
+        format,
+        width: image.width,
+        height: image.height,
+        size: buffer.len(),
+        hash: format!("{:x}", Sha256::digest(buffer)),
+        quality: result.quality,
+    })
+}
@@ -2,8 +2,10 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 use dssim::{Dssim, DssimImage};
+use imgref::ImgVec;
 
 use crate::common::Image;
+use crate::metric::Metric;
 
 pub struct Calculator {
     attr: Dssim,
@@ -19,6 +21,17 @@ impl Calculator {
         })
     }
 
+    /// Like `new` but keeps the SSIM map of the full resolution for `compare_regions`, which
+    /// otherwise returns `None`.
+    pub fn with_regions(original: &Image) -> Option<Self> {
+        let mut attr = Dssim::new();
+        attr.set_save_ssim_maps(1);
+        Some(Self {
+            original: attr.create_image(&original.to_rgbaplu())?,
+            attr,
+        })
+    }
+
     pub fn compare(&self, compressed: &Image) -> Option<f64> {
         let (dssim, _ssim_maps) = self.attr.compare(
             &self.original,
@@ -26,4 +39,86 @@ impl Calculator {
         );
         Some(dssim.into())
     }
+
+    /// Compare and also calculate DSSIM of the worst tile of the full resolution.
+    pub fn compare_regions(&self, compressed: &Image, tile_size: usize) -> Option<(f64, f64)> {
+        let (dssim, ssim_maps) = self.attr.compare(
+            &self.original,
+            self.attr.create_image(&compressed.to_rgbaplu())?,
+        );
+        Some((dssim.into(), worst_tile(&ssim_maps.first()?.map, tile_size)))
+    }
+}
+
+/// DSSIM of the worst tile of `map`. Tiles are scored with the formula dssim uses for each scale
+/// of the image: SSIM is one minus the mean absolute deviation of the pixels from their average,
+/// and converted to DSSIM like the weighted SSIM of the scales. A tile thus scores what an image
+/// looking like the tile at every scale would.
+fn worst_tile(map: &ImgVec<f32>, tile_size: usize) -> f64 {
+    let mut worst = 0.0f64;
+    for y in (0..map.height()).step_by(tile_size) {
+        for x in (0..map.width()).step_by(tile_size) {
+            let end = (x + tile_size).min(map.width());
+            let tile = || {
+                map.rows()
+                    .skip(y)
+                    .take(tile_size)
+                    .flat_map(|row| row[x..end].iter().map(|&ssim| f64::from(ssim)))
+            };
+            let count = tile().count() as f64;
+            let average = (tile().sum::<f64>() / count).max(0.0);
+            let ssim = 1.0 - tile().map(|ssim| (average - ssim).abs()).sum::<f64>() / count;
+            worst = worst.max(1.0 / ssim.max(f64::EPSILON) - 1.0);
+        }
+    }
+    worst
+}
+
+impl Metric for Calculator {
+    fn compare(&self, compressed: &Image) -> Option<f64> {
+        self.compare(compressed)
+    }
+
+    fn compare_regions(&self, compressed: &Image, tile_size: usize) -> Option<(f64, Option<f64>)> {
+        let (dssim, worst) = self.compare_regions(compressed, tile_size)?;
+        Some((dssim, Some(worst)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scores_worst_tile() {
+        // Left tile deviates from its average by 0.1 on every pixel, the right one not at all.
+        let mut map = ImgVec::new(vec![0.5f32; 4 * 2], 4, 2);
+        for (i, ssim) in map.rows_mut().flat_map(|row| &mut row[..2]).enumerate() {
+            *ssim = if i % 2 == 0 { 0.9 } else { 0.7 };
+        }
+        let worst = worst_tile(&map, 2);
+        assert!((worst - (1.0 / 0.9 - 1.0)).abs() < 1e-6, "{}", worst);
+        assert_eq!(worst_tile(&ImgVec::new(vec![0.5; 4 * 2], 4, 2), 2), 0.0);
+    }
+
+    #[test]
+    fn keeps_maps_only_for_regions() {
+        let image =
+            crate::png::read(&std::fs::read("images/image1-original.png").unwrap()).unwrap();
+        let compressed =
+            crate::png::read(&std::fs::read("images/image1-jpeg.png").unwrap()).unwrap();
+        assert!(Calculator::new(&image)
+            .unwrap()
+            .compare_regions(&compressed, 16)
+            .is_none());
+        let (dssim, worst) = Calculator::with_regions(&image)
+            .unwrap()
+            .compare_regions(&compressed, 16)
+            .unwrap();
+        assert_eq!(
+            Calculator::new(&image).unwrap().compare(&compressed),
+            Some(dssim)
+        );
+        assert!(worst > 0.0);
+    }
 }
@@ -86,10 +86,10 @@ pub fn read(buffer: &[u8]) -> ReadResult {
             },
         };
         if let Some(icc) = icc_data {
-            eprintln!("transforming to srgb...");
             match lcms2::Profile::new_icc(&icc) {
                 Ok(profile) => {
                     if !is_srgb(&profile) {
+                        log::debug!("transforming RGBA to sRGB");
                         let transform = lcms2::Transform::new(
                             &profile,
                             lcms2::PixelFormat::RGBA_8,
@@ -102,7 +102,7 @@ pub fn read(buffer: &[u8]) -> ReadResult {
                     }
                 }
                 Err(err) => {
-                    eprintln!("Failed to read ICC profile: {}", err);
+                    log::warn!("failed to read ICC profile: {}", err);
                 }
             }
         }
@@ -0,0 +1,214 @@
+// SPDX-FileCopyrightText: 2019-2020 Tuomas Siipola
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Worker running jobs given as JSON lines, one object per line, and answering each with a line
+//! describing its result.
+
+use std::io::{BufRead, Write};
+use std::sync::Mutex;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_json::{Map, Value};
+
+use crate::common::{supported_formats, Format};
+use crate::optimize::decode;
+use crate::output::Output;
+use crate::service::{compress, job_options};
+
+/// Run job given as a JSON object on a line of the worker's input and describe the result.
+pub fn run_job(job: &Value, jobs: usize) -> Result<Map<String, Value>, String> {
+    let input = match (job.get("input"), job.get("data")) {
+        (Some(path), None) => {
+            let path = path.as_str().ok_or("invalid input, expected path")?;
+            std::fs::read(path).map_err(|err| format!("failed to read {}: {}", path, err))?
+        }
+        (None, Some(data)) => {
+            let data = data
+                .as_str()
+                .ok_or("invalid data, expected base64 string")?;
+            BASE64
+                .decode(data)
+                .map_err(|err| format!("invalid data: {}", err))?
+        }
+        _ => return Err("expected either input or data".to_string()),
+    };
+    let input_format = Format::from_magic(&input).ok_or_else(|| {
+        format!(
+            "unknown image format, expected one of: {}",
+            supported_formats()
+        )
+    })?;
+    let formats = match job.get("format") {
+        None | Some(Value::Null) => vec![input_format],
+        Some(format) => match format.as_str() {
+            Some("auto") => Format::ALL.to_vec(),
+            Some(name) => {
+                vec![Format::from_ext(name).ok_or_else(|| format!("unknown format {}", name))?]
+            }
+            None => return Err("invalid format, expected string".to_string()),
+        },
+    };
+    // Parameters are named like in the service but with underscores as usual in JSON.
+    let param = |name: &str| {
+        job.get(name.replace('-', "_")).map(|value| match value {
+            Value::String(value) => value.clone(),
+            value => value.to_string(),
+        })
+    };
+    let options = job_options(param, formats[0], jobs)?;
+
+    let image =
+        decode(input_format, &input).map_err(|err| format!("failed to read image: {}", err))?;
+    let compressed = compress(image, &input, input_format, &formats, options)
+        .map_err(|err| format!("failed to compress image: {}", err))?;
+
+    let quality = if compressed.original {
+        "original".into()
+    } else {
+        match compressed.quality {
+            Some(quality) => quality.into(),
+            None => "lossless".into(),
+        }
+    };
+    let mut result = Map::new();
+    result.insert("format".to_string(), compressed.format.ext().into());
+    result.insert("mime".to_string(), compressed.format.mime().into());
+    result.insert("quality".to_string(), quality);
+    result.insert("metric".to_string(), compressed.metric.name().into());
+    result.insert("score".to_string(), compressed.score.into());
+    result.insert("input_size".to_string(), input.len().into());
+    result.insert("output_size".to_string(), compressed.buffer.len().into());
+    match job.get("output") {
+        None | Some(Value::Null) => {
+            let data = BASE64.encode(&compressed.buffer);
+            result.insert("data".to_string(), data.into());
+        }
+        Some(path) => {
+            let path = path.as_str().ok_or("invalid output, expected path")?;
+            Output::write_file(path)
+                .and_then(|output| output.write(&compressed.buffer))
+                .map_err(|err| format!("failed to write {}: {}", path, err))?;
+            result.insert("output".to_string(), path.into());
+        }
+    }
+    Ok(result)
+}
+
+/// Run jobs read from `input` and write their results to `output`. `concurrency` jobs are run at
+/// a time with `jobs` threads each.
+pub fn run(
+    input: impl BufRead,
+    output: impl Write + Send,
+    concurrency: usize,
+    jobs: usize,
+) -> Result<(), String> {
+    // Results are written as soon as the jobs finish, so they may come out of order and are
+    // matched to the jobs by their ids.
+    let (sender, receiver) = std::sync::mpsc::channel::<String>();
+    let receiver = Mutex::new(receiver);
+    let output = Mutex::new(output);
+    // Each worker has its own pool for optimizing one image at a time like in a batch.
+    let job_pools = (0..concurrency)
+        .map(|_| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(jobs)
+                .build()
+                .map_err(|err| err.to_string())
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    std::thread::scope(|scope| {
+        for job_pool in &job_pools {
+            let (receiver, output) = (&receiver, &output);
+            scope.spawn(move || loop {
+                let line = match receiver.lock().unwrap().recv() {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                let (id, result) = match serde_json::from_str::<Value>(&line) {
+                    Ok(job @ Value::Object(_)) => {
+                        let id = job.get("id").cloned().unwrap_or(Value::Null);
+                        (id, job_pool.install(|| run_job(&job, jobs)))
+                    }
+                    Ok(_) => (Value::Null, Err("expected job object".to_string())),
+                    Err(err) => (Value::Null, Err(format!("invalid job: {}", err))),
+                };
+                let mut fields = Map::new();
+                fields.insert("id".to_string(), id);
+                match result {
+                    Ok(result) => {
+                        fields.insert("status".to_string(), "ok".into());
+                        fields.extend(result);
+                    }
+                    Err(err) => {
+                        log::debug!("job failed: {}", err);
+                        fields.insert("status".to_string(), "error".into());
+                        fields.insert("error".to_string(), err.into());
+                    }
+                }
+                let mut output = output.lock().unwrap();
+                if writeln!(output, "{}", Value::Object(fields))
+                    .and_then(|_| output.flush())
+                    .is_err()
+                {
+                    break;
+                }
+            });
+        }
+
+        for line in input.lines() {
+            let line = line.map_err(|err| format!("failed to read job: {}", err))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+        drop(sender);
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    #[test]
+    fn answers_each_job() {
+        let dir = tempdir().unwrap();
+        let output = dir.path().join("output.jpeg");
+        let input = format!(
+            "{}\n\n[]\n{}\n",
+            json!({
+                "id": 1,
+                "input": "images/image1-original.png",
+                "format": "jpeg",
+                "output": output,
+            }),
+            json!({ "id": "two", "data": "!" }),
+        );
+        let mut results = Vec::new();
+        run(input.as_bytes(), &mut results, 1, 1).unwrap();
+
+        let results = String::from_utf8(results).unwrap();
+        let results: Vec<_> = results
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].get("id"), Some(&json!(1)));
+        assert_eq!(results[0].get("status"), Some(&Value::from("ok")));
+        assert_eq!(results[0].get("format"), Some(&Value::from("jpeg")));
+        assert!(output.is_file());
+        assert_eq!(results[1].get("id"), Some(&Value::Null));
+        assert_eq!(
+            results[1].get("error"),
+            Some(&Value::from("expected job object"))
+        );
+        assert_eq!(results[2].get("id"), Some(&Value::from("two")));
+        assert_eq!(results[2].get("status"), Some(&Value::from("error")));
+    }
+}